@@ -0,0 +1,251 @@
+// Maps raw model output to the meter scale, where a chart with meter m should
+// be rated in [m, m + 1).
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Kind {
+    /// Use the model output as is
+    #[default]
+    None,
+    /// Scale and offset
+    Affine,
+    /// Monotone piecewise linear fit
+    Isotonic,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Calibration {
+    Identity,
+    Affine {
+        scale: f64,
+        offset: f64,
+    },
+    // (raw, calibrated) points increasing in both, interpolated linearly
+    Isotonic {
+        points: Vec<(f64, f64)>,
+    },
+    // increasing (raw, meter) thresholds learned by the ordinal loss, where a chart
+    // below every threshold gets the lowest meter
+    Thresholds {
+        lowest: i32,
+        thresholds: Vec<(f64, i32)>,
+    },
+}
+
+pub fn suggested_meter(rating: f64) -> i32 {
+    rating.floor() as i32
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: f64) -> f64 {
+        match self {
+            Calibration::Identity => raw,
+            Calibration::Affine { scale, offset } => raw * scale + offset,
+            Calibration::Isotonic { points } => {
+                let i = points.partition_point(|(x, _)| *x < raw);
+                if i == 0 {
+                    points[0].1
+                } else if i == points.len() {
+                    points[i - 1].1
+                } else {
+                    let (x0, y0) = points[i - 1];
+                    let (x1, y1) = points[i];
+                    y0 + (y1 - y0) * (raw - x0) / (x1 - x0)
+                }
+            }
+            Calibration::Thresholds { lowest, thresholds } => {
+                let i = thresholds.partition_point(|(x, _)| *x <= raw);
+                let meter = if i == 0 { *lowest } else { thresholds[i - 1].1 };
+                meter as f64 + 0.5
+            }
+        }
+    }
+
+    // Fits raw ratings to meters, with targets of meter + 0.5.
+    pub fn fit(kind: Kind, raw: &[f64], meters: &[i32]) -> Self {
+        let targets = meters.iter().map(|m| *m as f64 + 0.5).collect::<Vec<_>>();
+        match kind {
+            Kind::None => Calibration::Identity,
+            Kind::Affine => {
+                let (scale, offset) = affine_fit(raw, &targets);
+                Calibration::Affine { scale, offset }
+            }
+            Kind::Isotonic => Calibration::Isotonic {
+                points: isotonic_fit(raw, &targets),
+            },
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let (kind, values) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "identity" => Some(Calibration::Identity),
+            "affine" => {
+                let (scale, offset) = values.split_once(',')?;
+                Some(Calibration::Affine {
+                    scale: scale.parse().ok()?,
+                    offset: offset.parse().ok()?,
+                })
+            }
+            "isotonic" => {
+                let points = values
+                    .split(',')
+                    .map(|p| {
+                        let (x, y) = p.split_once(':')?;
+                        Some((x.parse().ok()?, y.parse().ok()?))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Calibration::Isotonic { points })
+            }
+            "thresholds" => {
+                let mut values = values.split(',');
+                let lowest = values.next()?.parse().ok()?;
+                let thresholds = values
+                    .map(|t| {
+                        let (x, meter) = t.split_once(':')?;
+                        Some((x.parse().ok()?, meter.parse().ok()?))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Calibration::Thresholds { lowest, thresholds })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Calibration {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Calibration::Identity => write!(f, "identity"),
+            Calibration::Affine { scale, offset } => write!(f, "affine:{scale},{offset}"),
+            Calibration::Isotonic { points } => write!(
+                f,
+                "isotonic:{}",
+                points
+                    .iter()
+                    .map(|(x, y)| format!("{x}:{y}"))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Calibration::Thresholds { lowest, thresholds } => {
+                write!(f, "thresholds:{lowest}")?;
+                for (x, meter) in thresholds {
+                    write!(f, ",{x}:{meter}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Least squares (scale, offset) of targets against raw.
+fn affine_fit(raw: &[f64], targets: &[f64]) -> (f64, f64) {
+    let n = raw.len() as f64;
+    let mean_raw = raw.iter().sum::<f64>() / n;
+    let mean_target = targets.iter().sum::<f64>() / n;
+    let mut cov = 0.;
+    let mut var = 0.;
+    for (r, t) in raw.iter().zip(targets) {
+        cov += (r - mean_raw) * (t - mean_target);
+        var += (r - mean_raw) * (r - mean_raw);
+    }
+    let scale = if var == 0. { 0. } else { cov / var };
+    (scale, mean_target - scale * mean_raw)
+}
+
+// Pool adjacent violators: the non-decreasing step function of raw closest to
+// targets in least squares, as the (mean raw, mean target) of each step.
+fn isotonic_fit(raw: &[f64], targets: &[f64]) -> Vec<(f64, f64)> {
+    let mut order = (0..raw.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| raw[*a].total_cmp(&raw[*b]));
+    // (sum of raw, sum of targets, count)
+    let mut blocks = Vec::<(f64, f64, f64)>::new();
+    for i in order {
+        blocks.push((raw[i], targets[i], 1.));
+        while blocks.len() > 1 {
+            let (r1, t1, n1) = blocks[blocks.len() - 1];
+            let (r0, t0, n0) = blocks[blocks.len() - 2];
+            if t0 / n0 < t1 / n1 {
+                break;
+            }
+            blocks.pop();
+            *blocks.last_mut().unwrap() = (r0 + r1, t0 + t1, n0 + n1);
+        }
+    }
+    blocks.into_iter().map(|(r, t, n)| (r / n, t / n)).collect()
+}
+
+#[test]
+fn test_affine() {
+    let calibration = Calibration::fit(Kind::Affine, &[1., 2., 3.], &[10, 12, 14]);
+    assert_eq!(
+        calibration,
+        Calibration::Affine {
+            scale: 2.,
+            offset: 8.5
+        }
+    );
+    assert_eq!(calibration.apply(4.), 16.5);
+    assert_eq!(
+        Calibration::fit(Kind::Affine, &[1., 1.], &[10, 12]),
+        Calibration::Affine {
+            scale: 0.,
+            offset: 11.5
+        }
+    );
+}
+
+#[test]
+fn test_isotonic() {
+    let calibration = Calibration::fit(Kind::Isotonic, &[1., 3., 2., 4.], &[10, 12, 13, 15]);
+    // 2 and 3 are out of order and get pooled
+    assert_eq!(
+        calibration,
+        Calibration::Isotonic {
+            points: vec![(1., 10.5), (2.5, 13.), (4., 15.5)]
+        }
+    );
+    assert_eq!(calibration.apply(0.), 10.5);
+    assert_eq!(calibration.apply(1.75), 11.75);
+    assert_eq!(calibration.apply(2.5), 13.);
+    assert_eq!(calibration.apply(5.), 15.5);
+}
+
+#[test]
+fn test_thresholds() {
+    let calibration = Calibration::Thresholds {
+        lowest: 14,
+        thresholds: vec![(1.5, 15), (2.25, 17)],
+    };
+    assert_eq!(calibration.apply(0.), 14.5);
+    assert_eq!(calibration.apply(1.5), 15.5);
+    assert_eq!(calibration.apply(2.), 15.5);
+    assert_eq!(calibration.apply(3.), 17.5);
+}
+
+#[test]
+fn test_calibration_strings() {
+    for calibration in [
+        Calibration::Identity,
+        Calibration::Affine {
+            scale: 2.,
+            offset: -0.5,
+        },
+        Calibration::Isotonic {
+            points: vec![(1., 10.5), (2.5, 13.)],
+        },
+        Calibration::Thresholds {
+            lowest: 14,
+            thresholds: vec![(1.5, 15), (2.25, 17)],
+        },
+    ] {
+        assert_eq!(
+            Calibration::parse(&calibration.to_string()),
+            Some(calibration)
+        );
+    }
+    assert_eq!(Calibration::parse("affine:1"), None);
+    assert_eq!(Calibration::parse("thresholds:"), None);
+    assert_eq!(Calibration::parse("spline"), None);
+    assert_eq!(suggested_meter(16.99), 16);
+    assert_eq!(suggested_meter(17.), 17);
+}
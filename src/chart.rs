@@ -1,188 +1,432 @@
+use std::cell::OnceCell;
+use std::path::{Path, PathBuf};
+
 #[derive(PartialEq, Debug)]
 pub struct Note {
     pub time: f64,
+    // bit i is set if column i has an arrow
+    pub arrows: u8,
+}
+
+impl Note {
+    pub fn num_arrows(&self) -> u32 {
+        self.arrows.count_ones()
+    }
 }
 
 pub struct Chart {
     pub title: String,
     pub difficulty: String,
+    // whether it's the only chart read from its simfile, in which case
+    // descriptions leave out the difficulty
+    pub only_chart: bool,
+    // credit from the chart's #NOTES, empty if unknown
+    pub stepartist: String,
     pub notes: Vec<Note>,
     pub rating: i32,
+    // (beat, bpm) changes in order, starting at beat 0
+    pub bpms: Vec<(f64, f64)>,
+    // .sm file the chart was read from, None for synthetic charts
+    pub path: Option<PathBuf>,
+    // relative weight of the chart when training
+    pub weight: f64,
+    // how much the rating is trusted, from 0 to 1
+    pub confidence: f64,
+    // `features::features` of the chart, computed on first use since it doesn't
+    // change while training
+    pub features: OnceCell<Vec<f64>>,
 }
 
 impl Chart {
+    // Synthetic chart at a constant BPM with notes at `times`, stepped
+    // left-down-up-right so that alternating feet never cross over.
+    fn new(title: String, bpm: f64, times: Vec<f64>, rating: i32) -> Self {
+        Self {
+            title,
+            difficulty: "".to_owned(),
+            only_chart: false,
+            stepartist: "".to_owned(),
+            notes: times
+                .into_iter()
+                .enumerate()
+                .map(|(i, time)| Note {
+                    time,
+                    arrows: 1 << (i % 4),
+                })
+                .collect(),
+            rating,
+            bpms: vec![(0., bpm)],
+            path: None,
+            weight: 1.0,
+            confidence: 1.0,
+            features: OnceCell::new(),
+        }
+    }
+
+    // (beat, bpm) at `time`.
+    pub fn beat_and_bpm(&self, time: f64) -> (f64, f64) {
+        let mut beat = 0.;
+        let mut last_time = 0.;
+        let mut bpm = self.bpms[0].1;
+        for &(change_beat, change_bpm) in &self.bpms[1..] {
+            let change_time = last_time + (change_beat - beat) * 60. / bpm;
+            if time < change_time {
+                break;
+            }
+            beat = change_beat;
+            last_time = change_time;
+            bpm = change_bpm;
+        }
+        (beat + (time - last_time) * bpm / 60., bpm)
+    }
+
+    pub fn time_to_beat(&self, time: f64) -> f64 {
+        self.beat_and_bpm(time).0
+    }
+
     pub fn description(&self) -> String {
-        if self.difficulty.is_empty() {
+        if self.difficulty.is_empty() || self.only_chart {
             self.title.clone()
         } else {
             format!("{} ({})", self.title, self.difficulty)
         }
     }
+
+    // Directory containing the song directory, e.g. "pack" for "pack/song/song.sm".
+    pub fn pack(&self) -> String {
+        self.path
+            .as_ref()
+            .and_then(|p| p.parent()?.parent()?.file_name())
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    // Whether the chart is the one referred to by `path` (the trailing components of
+    // either the .sm file or the song directory) and `difficulty`. An empty
+    // `difficulty` matches every chart of the song, and charts without a difficulty
+    // match any difficulty.
+    pub fn matches(&self, path: &Path, difficulty: &str) -> bool {
+        let Some(sm_path) = &self.path else {
+            return false;
+        };
+        let path_matches =
+            sm_path.ends_with(path) || sm_path.parent().is_some_and(|p| p.ends_with(path));
+        path_matches
+            && (difficulty.is_empty()
+                || self.difficulty.is_empty()
+                || self.difficulty.eq_ignore_ascii_case(difficulty))
+    }
 }
 
 #[allow(dead_code)]
 impl Chart {
     pub fn stream_unbroken(bpm: f64, measures: i32, rating: i32) -> Self {
         let num_notes = measures * 16;
-        let mut notes = Vec::with_capacity(num_notes as usize);
+        let mut times = Vec::with_capacity(num_notes as usize);
         let dt = 15.0 / bpm;
         for i in 0..num_notes {
-            notes.push(Note {
-                time: dt * i as f64,
-            });
-        }
-        Self {
-            title: format!("{}@{}", measures, bpm),
-            difficulty: "".to_owned(),
-            notes,
-            rating,
+            times.push(dt * i as f64);
         }
+        Self::new(format!("{}@{}", measures, bpm), bpm, times, rating)
     }
 
     // n measures unbroken, n measure arrowless break, n measures unbroken
     pub fn stream_with_arrowless_break(bpm: f64, measures: i32, rating: i32) -> Self {
         let num_notes = measures * 16;
-        let mut notes = Vec::with_capacity(num_notes as usize);
+        let mut times = Vec::with_capacity(num_notes as usize);
         let dt = 15.0 / bpm;
         for i in 0..num_notes {
-            notes.push(Note {
-                time: dt * i as f64,
-            });
+            times.push(dt * i as f64);
         }
         for i in 0..num_notes {
-            notes.push(Note {
-                time: dt * (i + 2 * num_notes) as f64,
-            });
+            times.push(dt * (i + 2 * num_notes) as f64);
         }
-        Self {
-            title: format!("{}@{} (arrowless break)", measures, bpm),
-            difficulty: "".to_owned(),
-            notes,
+        Self::new(
+            format!("{}@{} (arrowless break)", measures, bpm),
+            bpm,
+            times,
             rating,
-        }
+        )
     }
 
     // n measures unbroken, n measures of 8th notes, n measures unbroken
     pub fn stream_with_8ths_break(bpm: f64, measures: i32, rating: i32) -> Self {
         let num_notes = measures * 16;
-        let mut notes = Vec::with_capacity(num_notes as usize);
+        let mut times = Vec::with_capacity(num_notes as usize);
         let dt = 15.0 / bpm;
         for i in 0..num_notes {
-            notes.push(Note {
-                time: dt * i as f64,
-            });
+            times.push(dt * i as f64);
         }
         for i in 0..(num_notes / 2) {
-            notes.push(Note {
-                time: dt * (2 * i + num_notes) as f64,
-            });
+            times.push(dt * (2 * i + num_notes) as f64);
         }
         for i in 0..num_notes {
-            notes.push(Note {
-                time: dt * (i + 2 * num_notes) as f64,
-            });
+            times.push(dt * (i + 2 * num_notes) as f64);
         }
-        Self {
-            title: format!("{}@{} (8th notes break)", measures, bpm),
-            difficulty: "".to_owned(),
-            notes,
+        Self::new(
+            format!("{}@{} (8th notes break)", measures, bpm),
+            bpm,
+            times,
             rating,
-        }
+        )
     }
+}
 
-    pub fn presets(only_longest: bool) -> Vec<Self> {
-        let mut charts = vec![
-            Chart::stream_unbroken(170., 96, 15),
-            Chart::stream_unbroken(170., 128, 15),
-            Chart::stream_unbroken(170., 192, 16),
-            Chart::stream_unbroken(170., 256, 16),
-            Chart::stream_unbroken(170., 384, 17),
-            Chart::stream_unbroken(170., 512, 17),
-            Chart::stream_unbroken(180., 64, 15),
-            Chart::stream_unbroken(180., 96, 15),
-            Chart::stream_unbroken(180., 128, 16),
-            Chart::stream_unbroken(180., 192, 16),
-            Chart::stream_unbroken(180., 256, 17),
-            Chart::stream_unbroken(180., 384, 17),
-            Chart::stream_unbroken(180., 512, 18),
-            Chart::stream_unbroken(190., 48, 15),
-            Chart::stream_unbroken(190., 64, 15),
-            Chart::stream_unbroken(190., 96, 16),
-            Chart::stream_unbroken(190., 128, 17),
-            Chart::stream_unbroken(190., 192, 17),
-            Chart::stream_unbroken(190., 256, 18),
-            Chart::stream_unbroken(190., 384, 18),
-            Chart::stream_unbroken(190., 512, 19),
-            Chart::stream_unbroken(200., 32, 15),
-            Chart::stream_unbroken(200., 48, 15),
-            Chart::stream_unbroken(200., 64, 16),
-            Chart::stream_unbroken(200., 96, 17),
-            Chart::stream_unbroken(200., 128, 17),
-            Chart::stream_unbroken(200., 192, 18),
-            Chart::stream_unbroken(200., 256, 19),
-            Chart::stream_unbroken(200., 384, 19),
-            Chart::stream_unbroken(200., 512, 20),
-            Chart::stream_unbroken(210., 32, 15),
-            Chart::stream_unbroken(210., 48, 16),
-            Chart::stream_unbroken(210., 64, 17),
-            Chart::stream_unbroken(210., 96, 18),
-            Chart::stream_unbroken(210., 128, 18),
-            Chart::stream_unbroken(210., 192, 19),
-            Chart::stream_unbroken(210., 256, 20),
-            Chart::stream_unbroken(210., 384, 20),
-            Chart::stream_unbroken(210., 512, 21),
-            Chart::stream_unbroken(220., 32, 16),
-            Chart::stream_unbroken(220., 48, 17),
-            Chart::stream_unbroken(220., 64, 18),
-            Chart::stream_unbroken(220., 96, 19),
-            Chart::stream_unbroken(220., 128, 19),
-            Chart::stream_unbroken(220., 192, 20),
-            Chart::stream_unbroken(220., 256, 21),
-            Chart::stream_unbroken(220., 384, 22),
-            Chart::stream_unbroken(220., 512, 22),
-            Chart::stream_unbroken(230., 32, 17),
-            Chart::stream_unbroken(230., 48, 18),
-            Chart::stream_unbroken(230., 64, 19),
-            Chart::stream_unbroken(230., 96, 20),
-            Chart::stream_unbroken(230., 128, 20),
-            Chart::stream_unbroken(230., 192, 21),
-            Chart::stream_unbroken(230., 256, 22),
-            Chart::stream_unbroken(230., 384, 22),
-            Chart::stream_unbroken(230., 512, 23),
-        ];
-
-        if only_longest {
-            charts.retain(|c| c.notes.len() == 512 * 16);
-        }
-        charts
+impl Chart {
+    // Synthetic chart from whitespace separated sections, each either
+    // `[quantization:]measures[@bpm]` of notes, where quantization is 8ths, 16ths
+    // (the default), 24ths or 32nds, or `(measures[@bpm])` of rest. Sections
+    // without a BPM continue at the previous one, e.g. "32@180 (8) 8ths:16 64@200".
+    pub fn from_spec(spec: &str, rating: i32) -> Option<Self> {
+        let mut bpms = Vec::<(f64, f64)>::new();
+        let mut times = Vec::new();
+        let mut beat = 0.;
+        let mut time = 0.;
+        for section in spec.split_whitespace() {
+            let (notes_per_measure, notes) = match section
+                .strip_prefix('(')
+                .and_then(|s| s.strip_suffix(')'))
+            {
+                Some(rest) => (0, rest),
+                None => match section.split_once(':') {
+                    Some((quantization, notes)) => match quantization {
+                        "8ths" => (8, notes),
+                        "16ths" => (16, notes),
+                        "24ths" => (24, notes),
+                        "32nds" => (32, notes),
+                        _ => {
+                            println!("Invalid quantization {quantization:?}, expected 8ths, 16ths, 24ths or 32nds");
+                            return None;
+                        }
+                    },
+                    None => (16, section),
+                },
+            };
+            let (measures, bpm) = match notes.split_once('@') {
+                Some((measures, bpm)) => (measures, Some(bpm)),
+                None => (notes, None),
+            };
+            let Ok(measures) = measures.parse::<u32>() else {
+                println!("Invalid measures in {section:?}");
+                return None;
+            };
+            if let Some(bpm) = bpm {
+                let Some(bpm) = bpm.parse::<f64>().ok().filter(|b| *b > 0.) else {
+                    println!("Invalid BPM in {section:?}");
+                    return None;
+                };
+                if bpms.last().map(|(_, b)| *b) != Some(bpm) {
+                    bpms.push((beat, bpm));
+                }
+            }
+            let Some(&(_, bpm)) = bpms.last() else {
+                println!("The first section needs a BPM: {section:?}");
+                return None;
+            };
+            let measure_time = 240. / bpm;
+            for i in 0..measures * notes_per_measure {
+                times.push(time + measure_time * i as f64 / notes_per_measure as f64);
+            }
+            beat += 4. * measures as f64;
+            time += measure_time * measures as f64;
+        }
+        if bpms.is_empty() {
+            println!("Empty chart spec");
+            return None;
+        }
+        let mut chart = Self::new(spec.to_owned(), bpms[0].1, times, rating);
+        chart.bpms = bpms;
+        Some(chart)
     }
 }
 
+#[test]
+fn test_matches() {
+    let mut chart = Chart::stream_unbroken(120.0, 2, 42);
+    assert!(!chart.matches(Path::new("song.sm"), ""));
+    chart.path = Some(PathBuf::from("/songs/pack/song/song.sm"));
+    assert_eq!(chart.pack(), "pack");
+    assert!(chart.matches(Path::new("song.sm"), "Hard"));
+    assert!(chart.matches(Path::new("pack/song"), "Hard"));
+    assert!(chart.matches(Path::new("pack/song/song.sm"), "Hard"));
+    assert!(!chart.matches(Path::new("ong.sm"), "Hard"));
+    assert!(!chart.matches(Path::new("other/song"), "Hard"));
+    chart.difficulty = "Hard".to_owned();
+    assert!(chart.matches(Path::new("pack/song"), "hard"));
+    assert!(!chart.matches(Path::new("pack/song"), "Medium"));
+    assert!(chart.matches(Path::new("pack/song"), ""));
+    // the only chart of a song is described without its difficulty but still only
+    // matches it
+    chart.only_chart = true;
+    assert_eq!(chart.description(), chart.title);
+    assert!(!chart.matches(Path::new("pack/song"), "Challenge"));
+}
+
+#[test]
+fn test_time_to_beat() {
+    let mut chart = Chart::stream_unbroken(120.0, 2, 42);
+    assert_eq!(chart.time_to_beat(0.), 0.);
+    assert_eq!(chart.time_to_beat(1.5), 3.);
+    assert_eq!(chart.beat_and_bpm(1.5), (3., 120.));
+    chart.bpms = vec![(0., 60.), (4., 240.), (8., 60.)];
+    assert_eq!(chart.time_to_beat(2.), 2.);
+    assert_eq!(chart.beat_and_bpm(4.), (4., 240.));
+    assert_eq!(chart.time_to_beat(4.5), 6.);
+    assert_eq!(chart.beat_and_bpm(6.), (9., 60.));
+}
+
+#[test]
+fn test_from_spec() {
+    let chart = Chart::from_spec("2@120 (1) 8ths:1 1@60 32nds:1@120", 0).unwrap();
+    assert_eq!(chart.title, "2@120 (1) 8ths:1 1@60 32nds:1@120");
+    assert_eq!(chart.bpms, vec![(0., 120.), (16., 60.), (20., 120.)]);
+    assert_eq!(chart.notes.len(), 32 + 8 + 16 + 32);
+    assert_eq!(chart.notes[1].time, 0.125);
+    // after 2 measures of 16ths and a measure of rest at 120 BPM
+    assert_eq!(chart.notes[32].time, 6.);
+    assert_eq!(chart.notes[33].time, 6.25);
+    assert_eq!(chart.notes[40].time, 8.);
+    assert_eq!(chart.notes[41].time, 8.25);
+    assert_eq!(chart.notes[56].time, 12.);
+    assert_eq!(chart.notes[57].time, 12.0625);
+    assert_eq!(
+        Chart::from_spec("64@200", 17).unwrap().notes,
+        Chart::stream_unbroken(200., 64, 17).notes
+    );
+    assert!(Chart::from_spec("", 0).is_none());
+    assert!(Chart::from_spec("16", 0).is_none());
+    assert!(Chart::from_spec("12ths:16@120", 0).is_none());
+    assert!(Chart::from_spec("16@fast", 0).is_none());
+    assert!(Chart::from_spec("(x)@120", 0).is_none());
+}
+
 #[test]
 fn test_stream_charts() {
     let chart = Chart::stream_unbroken(120.0, 2, 42);
     assert_eq!(chart.notes.len(), 32);
-    assert_eq!(chart.notes[0], Note { time: 0.0 });
-    assert_eq!(chart.notes[1], Note { time: 0.125 });
-    assert_eq!(chart.notes[2], Note { time: 0.25 });
+    assert_eq!(
+        chart.notes[0],
+        Note {
+            time: 0.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[1],
+        Note {
+            time: 0.125,
+            arrows: 2
+        }
+    );
+    assert_eq!(
+        chart.notes[2],
+        Note {
+            time: 0.25,
+            arrows: 4
+        }
+    );
 
     let chart = Chart::stream_with_arrowless_break(120.0, 2, 42);
     assert_eq!(chart.notes.len(), 64);
-    assert_eq!(chart.notes[0], Note { time: 0.0 });
-    assert_eq!(chart.notes[1], Note { time: 0.125 });
-    assert_eq!(chart.notes[2], Note { time: 0.25 });
-    assert_eq!(chart.notes[31], Note { time: 3.875 });
-    assert_eq!(chart.notes[32], Note { time: 8.0 });
-    assert_eq!(chart.notes[33], Note { time: 8.125 });
+    assert_eq!(
+        chart.notes[0],
+        Note {
+            time: 0.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[1],
+        Note {
+            time: 0.125,
+            arrows: 2
+        }
+    );
+    assert_eq!(
+        chart.notes[2],
+        Note {
+            time: 0.25,
+            arrows: 4
+        }
+    );
+    assert_eq!(
+        chart.notes[31],
+        Note {
+            time: 3.875,
+            arrows: 8
+        }
+    );
+    assert_eq!(
+        chart.notes[32],
+        Note {
+            time: 8.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[33],
+        Note {
+            time: 8.125,
+            arrows: 2
+        }
+    );
 
     let chart = Chart::stream_with_8ths_break(120.0, 2, 42);
     assert_eq!(chart.notes.len(), 80);
-    assert_eq!(chart.notes[0], Note { time: 0.0 });
-    assert_eq!(chart.notes[1], Note { time: 0.125 });
-    assert_eq!(chart.notes[2], Note { time: 0.25 });
-    assert_eq!(chart.notes[31], Note { time: 3.875 });
-    assert_eq!(chart.notes[32], Note { time: 4.0 });
-    assert_eq!(chart.notes[33], Note { time: 4.25 });
-    assert_eq!(chart.notes[48], Note { time: 8.0 });
-    assert_eq!(chart.notes[49], Note { time: 8.125 });
+    assert_eq!(
+        chart.notes[0],
+        Note {
+            time: 0.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[1],
+        Note {
+            time: 0.125,
+            arrows: 2
+        }
+    );
+    assert_eq!(
+        chart.notes[2],
+        Note {
+            time: 0.25,
+            arrows: 4
+        }
+    );
+    assert_eq!(
+        chart.notes[31],
+        Note {
+            time: 3.875,
+            arrows: 8
+        }
+    );
+    assert_eq!(
+        chart.notes[32],
+        Note {
+            time: 4.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[33],
+        Note {
+            time: 4.25,
+            arrows: 2
+        }
+    );
+    assert_eq!(
+        chart.notes[48],
+        Note {
+            time: 8.0,
+            arrows: 1
+        }
+    );
+    assert_eq!(
+        chart.notes[49],
+        Note {
+            time: 8.125,
+            arrows: 2
+        }
+    );
 }
@@ -0,0 +1,114 @@
+use crate::chart::Chart;
+use crate::features::{is_stream, notes_per_measure};
+use crate::timeline::{json_string, Point};
+
+// Fraction of the peak fatigue that counts as part of the crux.
+const PEAK_FRACTION: f64 = 0.9;
+
+// The hardest part of a chart: where fatigue peaks and what leads into it.
+#[derive(Debug, PartialEq)]
+pub struct Crux {
+    pub peak: f64,
+    pub peak_time: f64,
+    pub peak_measure: usize,
+    // first and last measure around the peak with fatigue within PEAK_FRACTION of it
+    pub measures: (usize, usize),
+    // first and last measure of the last run of stream that ends in the crux
+    // measures up to the peak
+    pub stream: Option<(usize, usize)>,
+}
+
+// Locates the crux in a timeline of the chart, None without notes.
+pub fn crux(chart: &Chart, points: &[Point]) -> Option<Crux> {
+    let (peak_index, peak) = points
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.value.total_cmp(&b.value))?;
+    let threshold = peak.value * PEAK_FRACTION;
+    let first = points[..peak_index]
+        .iter()
+        .rposition(|p| p.value < threshold)
+        .map_or(0, |i| i + 1);
+    let last = points[peak_index..]
+        .iter()
+        .position(|p| p.value < threshold)
+        .map_or(points.len(), |i| peak_index + i)
+        - 1;
+    let measures = (points[first].measure, points[last].measure);
+    let notes_per_measure = notes_per_measure(chart);
+    let in_stream = |m: &usize| notes_per_measure.get(*m).is_some_and(|n| is_stream(*n));
+    // fatigue can keep rising for a bit after stream ends, e.g. into 8ths
+    let stream = (measures.0..=peak.measure)
+        .rev()
+        .find(in_stream)
+        .map(|end| {
+            let start = (0..end).rev().find(|m| !in_stream(m)).map_or(0, |m| m + 1);
+            (start, end)
+        });
+    Some(Crux {
+        peak: peak.value,
+        peak_time: peak.time,
+        peak_measure: peak.measure,
+        measures,
+        stream,
+    })
+}
+
+impl Crux {
+    pub fn description(&self) -> String {
+        let mut description = format!(
+            "peak {:.2} at measure {} ({:.1}s), crux measures {}-{}",
+            self.peak, self.peak_measure, self.peak_time, self.measures.0, self.measures.1
+        );
+        if let Some((start, end)) = self.stream {
+            description.push_str(&format!(
+                ", after {} measures of stream ({start}-{end})",
+                end - start + 1
+            ));
+        }
+        description
+    }
+
+    pub fn to_json(&self, chart: &Chart) -> String {
+        format!(
+            "{{\"title\":{},\"difficulty\":{},\"rating\":{},\"peak\":{},\"peak_time\":{},\"peak_measure\":{},\"measures\":[{},{}],\"stream\":{}}}",
+            json_string(&chart.title),
+            json_string(&chart.difficulty),
+            chart.rating,
+            self.peak,
+            self.peak_time,
+            self.peak_measure,
+            self.measures.0,
+            self.measures.1,
+            match self.stream {
+                Some((start, end)) => format!("[{start},{end}]"),
+                None => "null".to_owned(),
+            }
+        )
+    }
+}
+
+#[test]
+fn test_crux() {
+    use crate::rate::{constants, Fatigue, RatingModel};
+    use crate::timeline::timeline;
+    let chart = Chart::from_spec("8@150 (4) 16@150 8ths:8", 0).unwrap();
+    let states = Fatigue
+        .rate(&chart, &constants(&Fatigue.default_params()))
+        .1;
+    let found = crux(&chart, &timeline(&chart, &states[1..])).unwrap();
+    // fatigue peaks just after the longer stream, on the first 8th
+    assert_eq!(found.peak_measure, 28);
+    assert!((found.peak_time - 28. * 1.6).abs() < 1e-9);
+    assert_eq!(found.stream, Some((12, 27)));
+    assert!(found.measures.0 > 12 && found.measures.0 < 27);
+    assert!(found.measures.1 >= 28);
+    assert!(found.to_json(&chart).ends_with("\"stream\":[12,27]}"));
+    let rested = Chart::from_spec("8@150 (4) 8ths:4@150", 0).unwrap();
+    let states = Fatigue
+        .rate(&rested, &constants(&Fatigue.default_params()))
+        .1;
+    let found = crux(&rested, &timeline(&rested, &states[1..])).unwrap();
+    assert_eq!(found.stream, Some((0, 7)));
+    assert_eq!(crux(&chart, &[]), None);
+}
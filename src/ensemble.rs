@@ -0,0 +1,103 @@
+use crate::calibrate::Calibration;
+use crate::chart::Chart;
+use crate::rate::{constants, RatingModel};
+use autodiff::F1;
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Combine {
+    #[default]
+    Mean,
+    Median,
+}
+
+// Combined rating of several predictions for the same chart.
+#[derive(Debug, PartialEq)]
+pub struct Prediction {
+    pub rating: f64,
+    pub stddev: f64,
+    // 5th and 95th percentiles
+    pub low: f64,
+    pub high: f64,
+}
+
+// Linearly interpolated percentile of sorted values, `p` from 0 to 1.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let i = p * (sorted.len() - 1) as f64;
+    let (lo, hi) = (i.floor() as usize, i.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (i - lo as f64)
+}
+
+pub fn combine(values: &[f64], combine: Combine) -> Prediction {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n;
+    Prediction {
+        rating: match combine {
+            Combine::Mean => mean,
+            Combine::Median => percentile(&sorted, 0.5),
+        },
+        stddev: variance.sqrt(),
+        low: percentile(&sorted, 0.05),
+        high: percentile(&sorted, 0.95),
+    }
+}
+
+// Several models with their params and calibrations, e.g. trained from different
+// seeds or on different folds.
+pub struct Ensemble {
+    pub members: Vec<(Box<dyn RatingModel>, Vec<F1>, Calibration)>,
+    pub combine: Combine,
+}
+
+impl Ensemble {
+    pub fn new(
+        members: Vec<(Box<dyn RatingModel>, Vec<f64>, Calibration)>,
+        combine: Combine,
+    ) -> Self {
+        Self {
+            members: members
+                .into_iter()
+                .map(|(model, params, calibration)| (model, constants(&params), calibration))
+                .collect(),
+            combine,
+        }
+    }
+
+    // Returns the combined calibrated rating and the first member's uncalibrated
+    // state after each note.
+    pub fn rate(&self, chart: &Chart) -> (Prediction, Vec<(f64, f64)>) {
+        let mut ratings = Vec::with_capacity(self.members.len());
+        let mut states = Vec::new();
+        for (i, (model, params, calibration)) in self.members.iter().enumerate() {
+            let (rating, member_states) = model.rate(chart, params);
+            ratings.push(calibration.apply(rating.x));
+            if i == 0 {
+                states = member_states;
+            }
+        }
+        (combine(&ratings, self.combine), states)
+    }
+}
+
+#[test]
+fn test_combine() {
+    assert_eq!(
+        combine(&[2.], Combine::Mean),
+        Prediction {
+            rating: 2.,
+            stddev: 0.,
+            low: 2.,
+            high: 2.
+        }
+    );
+    let values = [4., 1., 2., 3., 10.];
+    let mean = combine(&values, Combine::Mean);
+    assert_eq!(mean.rating, 4.);
+    assert_eq!(mean.stddev, 10f64.sqrt());
+    assert_eq!(mean.low, 1.2);
+    assert!((mean.high - 8.8).abs() < 1e-9);
+    assert_eq!(combine(&values, Combine::Median).rating, 3.);
+    assert_eq!(combine(&[1., 2.], Combine::Median).rating, 1.5);
+}
@@ -0,0 +1,239 @@
+use crate::chart::Chart;
+use crate::rate::{constants, Fatigue, ModelState, RatingModel};
+use crate::timeline;
+use autodiff::F1;
+
+pub const FEATURE_NAMES: [&str; 10] = [
+    "peak_nps_2s",
+    "peak_nps_8s",
+    "peak_nps_32s",
+    "stream_measures",
+    "longest_stream",
+    "max_bpm",
+    "jump_density",
+    "hand_density",
+    "crossover_density",
+    "peak_fatigue",
+];
+
+// Windows in seconds of the peak NPS features, which come first in `features`.
+pub const PEAK_NPS_WINDOWS: [f64; 3] = [2., 8., 32.];
+const PEAK_NPS_SCALE: f64 = 10.;
+
+// Most notes in any `window` seconds, per second.
+fn peak_nps(chart: &Chart, window: f64) -> f64 {
+    let mut start = 0;
+    let mut peak = 0;
+    for (end, note) in chart.notes.iter().enumerate() {
+        while chart.notes[start].time <= note.time - window {
+            start += 1;
+        }
+        peak = peak.max(end + 1 - start);
+    }
+    peak as f64 / window
+}
+
+// Notes in each measure of the chart.
+pub fn notes_per_measure(chart: &Chart) -> Vec<usize> {
+    let mut notes_per_measure = Vec::<usize>::new();
+    for note in &chart.notes {
+        let measure = timeline::measure(chart.time_to_beat(note.time));
+        if notes_per_measure.len() <= measure {
+            notes_per_measure.resize(measure + 1, 0);
+        }
+        notes_per_measure[measure] += 1;
+    }
+    notes_per_measure
+}
+
+// Whether a measure with `notes` notes counts as stream.
+pub fn is_stream(notes: usize) -> bool {
+    notes >= 16
+}
+
+// Measures with at least 16 notes, and the longest run of consecutive ones.
+fn stream_measures(chart: &Chart) -> (usize, usize) {
+    let mut total = 0;
+    let mut run = 0;
+    let mut longest = 0;
+    for notes in notes_per_measure(chart) {
+        if is_stream(notes) {
+            total += 1;
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    (total, longest)
+}
+
+// Steps per note where a foot crosses over to the opposite side of the pad,
+// assuming single arrows alternate feet and jumps reset which foot is next.
+fn crossover_density(chart: &Chart) -> f64 {
+    const LEFT: u8 = 0b0001;
+    const RIGHT: u8 = 0b1000;
+    let mut next_foot_left = None;
+    let mut crossovers = 0;
+    for note in &chart.notes {
+        if note.num_arrows() != 1 {
+            next_foot_left = None;
+            continue;
+        }
+        // without a previous step, start with whichever foot doesn't cross over
+        let left = next_foot_left.unwrap_or(note.arrows != RIGHT);
+        if (left && note.arrows == RIGHT) || (!left && note.arrows == LEFT) {
+            crossovers += 1;
+        }
+        next_foot_left = Some(!left);
+    }
+    crossovers as f64 / chart.notes.len().max(1) as f64
+}
+
+// Features of the chart in the order of `FEATURE_NAMES`, scaled to be roughly
+// between 0 and a few for typical charts so that gradient descent treats their
+// weights similarly.
+pub fn features(chart: &Chart) -> Vec<f64> {
+    let num_notes = chart.notes.len().max(1) as f64;
+    let density = |min_arrows: u32| {
+        chart
+            .notes
+            .iter()
+            .filter(|n| n.num_arrows() >= min_arrows)
+            .count() as f64
+            / num_notes
+    };
+    let (stream, longest_stream) = stream_measures(chart);
+    let max_bpm = chart.bpms.iter().map(|(_, bpm)| *bpm).fold(0., f64::max);
+    let peak_fatigue = Fatigue
+        .rate(chart, &constants(&Fatigue.default_params()))
+        .0
+        .x;
+    let mut features = PEAK_NPS_WINDOWS
+        .iter()
+        .map(|window| peak_nps(chart, *window) / PEAK_NPS_SCALE)
+        .collect::<Vec<_>>();
+    features.extend([
+        stream as f64 / 100.,
+        longest_stream as f64 / 100.,
+        max_bpm / 100.,
+        density(2),
+        density(3),
+        crossover_density(chart),
+        peak_fatigue / 10.,
+    ]);
+    features
+}
+
+// `features` of the chart, computed on first use.
+pub fn cached_features(chart: &Chart) -> &[f64] {
+    chart.features.get_or_init(|| features(chart))
+}
+
+// Peak NPS over each of `PEAK_NPS_WINDOWS`.
+pub fn cached_peak_nps(chart: &Chart) -> Vec<f64> {
+    cached_features(chart)[..PEAK_NPS_WINDOWS.len()]
+        .iter()
+        .map(|f| f * PEAK_NPS_SCALE)
+        .collect()
+}
+
+// Ridge regression over `features`: a bias plus a weight per feature, with the
+// squared weights penalized while training.
+pub struct LinearFeatures;
+
+impl RatingModel for LinearFeatures {
+    fn name(&self) -> &'static str {
+        "linear"
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        let mut names = vec!["bias".to_owned()];
+        names.extend(FEATURE_NAMES.iter().map(|n| n.to_string()));
+        names
+    }
+
+    fn param_bounds(&self) -> Vec<(f64, f64)> {
+        vec![(f64::NEG_INFINITY, f64::INFINITY); FEATURE_NAMES.len() + 1]
+    }
+
+    // Starts out rating charts by peak fatigue alone, like the fatigue model.
+    fn default_params(&self) -> Vec<f64> {
+        let mut params = vec![0.; FEATURE_NAMES.len() + 1];
+        params[FEATURE_NAMES.len()] = 10.;
+        params
+    }
+
+    fn state<'a>(&self, chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a> {
+        let mut rating = params[0];
+        for (feature, weight) in cached_features(chart).iter().zip(&params[1..]) {
+            rating += *weight * F1::cst(*feature);
+        }
+        Box::new(LinearFeaturesState { rating })
+    }
+
+    fn regularization(&self, params: &[F1]) -> F1 {
+        const L2: f64 = 0.001;
+        let mut sum = F1::cst(0.);
+        for weight in &params[1..] {
+            sum += *weight * *weight;
+        }
+        sum * F1::cst(L2)
+    }
+}
+
+struct LinearFeaturesState {
+    rating: F1,
+}
+
+impl ModelState for LinearFeaturesState {
+    fn step(&mut self, _time: f64) {}
+
+    fn current(&self) -> f64 {
+        self.rating.x
+    }
+
+    fn score(&self) -> F1 {
+        self.rating
+    }
+}
+
+#[test]
+fn test_features() {
+    let chart = Chart::stream_unbroken(120., 64, 0);
+    let features = features(&chart);
+    // 16ths at 120 BPM are 8 notes per second
+    assert_eq!(features[0], 0.8);
+    assert_eq!(features[2], 0.8);
+    assert_eq!(features[3], 0.64);
+    assert_eq!(features[4], 0.64);
+    assert_eq!(features[5], 1.2);
+    assert_eq!(features[6], 0.);
+    assert_eq!(features[8], 0.);
+
+    let chart = Chart::stream_with_8ths_break(120., 16, 0);
+    let (stream, longest) = stream_measures(&chart);
+    assert_eq!((stream, longest), (32, 16));
+
+    let mut chart = Chart::stream_unbroken(120., 1, 0);
+    chart.notes.truncate(7);
+    for (note, arrows) in chart.notes.iter_mut().zip([1, 8, 1, 8, 3, 8, 1]) {
+        note.arrows = arrows;
+    }
+    assert_eq!(crossover_density(&chart), 0.);
+    // starting on down with the left foot puts the right foot on left and the left
+    // foot on right, until the jump resets feet
+    chart.notes[0].arrows = 2;
+    chart.notes[1].arrows = 1;
+    chart.notes[2].arrows = 8;
+    assert_eq!(crossover_density(&chart), 2. / 7.);
+}
+
+#[test]
+fn test_linear_features() {
+    let params = constants(&LinearFeatures.default_params());
+    let chart = Chart::stream_unbroken(180., 64, 0);
+    let fatigue = Fatigue.rate(&chart, &constants(&Fatigue.default_params()));
+    assert!((LinearFeatures.rate(&chart, &params).0.x - fatigue.0.x).abs() < 1e-9);
+    assert_eq!(chart.features.get(), Some(&features(&chart)));
+}
@@ -0,0 +1,162 @@
+use crate::chart::Chart;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum XAxis {
+    /// Seconds since the start of the chart
+    #[default]
+    Time,
+    /// Beats since the start of the chart, following BPM changes
+    Beats,
+    /// Measures of 4 beats since the start of the chart
+    Measures,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl Format {
+    // From the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "svg" => Some(Format::Svg),
+            "pdf" => Some(Format::Pdf),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Svg => "svg",
+            Format::Pdf => "pdf",
+        }
+    }
+}
+
+pub struct Options {
+    pub x_axis: XAxis,
+    pub format: Format,
+    // in pixels, or hundredths of an inch for PDFs
+    pub width: u32,
+    pub height: u32,
+}
+
+// Case insensitive regex to select charts with.
+pub fn pattern(pattern: &str) -> Result<regex::Regex, regex::Error> {
+    regex::RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+}
+
+fn x(chart: &Chart, time: f64, x_axis: XAxis) -> f64 {
+    match x_axis {
+        XAxis::Time => time,
+        XAxis::Beats => chart.time_to_beat(time),
+        XAxis::Measures => chart.time_to_beat(time) / 4.,
+    }
+}
+
+// Plots each chart's (time, value) states on one graph.
+pub fn fatigues(
+    path: &Path,
+    y_label: &str,
+    charts: &[(&Chart, &Vec<(f64, f64)>)],
+    options: &Options,
+) {
+    use gnuplot::{AxesCommon, PlotOption};
+
+    let mut fg = gnuplot::Figure::new();
+    let x_label = match options.x_axis {
+        XAxis::Time => "time",
+        XAxis::Beats => "beat",
+        XAxis::Measures => "measure",
+    };
+    let a = fg
+        .axes2d()
+        .set_x_label(x_label, &[])
+        .set_y_label(y_label, &[]);
+    for (chart, fatigue_times) in charts {
+        let xs = fatigue_times
+            .iter()
+            .map(|(t, _)| x(chart, *t, options.x_axis))
+            .collect::<Vec<_>>();
+        let fatigues = fatigue_times.iter().map(|(_, a)| *a).collect::<Vec<_>>();
+        let caption = chart.description().replace('@', "\\@");
+        a.points(xs, fatigues, &[PlotOption::Caption(&caption)]);
+    }
+    match options.format {
+        Format::Png => fg.save_to_png(path, options.width, options.height),
+        Format::Svg => fg.save_to_svg(path, options.width, options.height),
+        Format::Pdf => fg.save_to_pdf(
+            path,
+            options.width as f32 / 100.,
+            options.height as f32 / 100.,
+        ),
+    }
+    .unwrap();
+    println!("drew fatigue graph to {:?}", path);
+}
+
+// Plots training and validation error against iteration, marking restarts.
+pub fn history(path: &Path, history: &[crate::train::HistoryEntry]) {
+    use gnuplot::{AxesCommon, PlotOption};
+
+    let mut fg = gnuplot::Figure::new();
+    let a = fg
+        .axes2d()
+        .set_x_label("iteration", &[])
+        .set_y_label("error", &[])
+        .set_y_log(Some(10.));
+    let iterations = history.iter().map(|h| h.iteration).collect::<Vec<_>>();
+    let train_errs = history.iter().map(|h| h.train_err).collect::<Vec<_>>();
+    a.lines(&iterations, &train_errs, &[PlotOption::Caption("train")]);
+    let validation = history
+        .iter()
+        .filter_map(|h| Some((h.iteration, h.validation_err?)))
+        .collect::<Vec<_>>();
+    if !validation.is_empty() {
+        a.lines(
+            validation.iter().map(|(i, _)| *i),
+            validation.iter().map(|(_, e)| *e),
+            &[PlotOption::Caption("validation")],
+        );
+    }
+    let restarts = history.iter().filter(|h| h.restart).collect::<Vec<_>>();
+    a.points(
+        restarts.iter().map(|h| h.iteration),
+        restarts.iter().map(|h| h.train_err),
+        &[PlotOption::Caption("restart"), PlotOption::PointSymbol('O')],
+    );
+    fg.save_to_png(path, 1280, 720).unwrap();
+    println!("drew training history graph to {:?}", path);
+}
+
+#[test]
+fn test_pattern() {
+    assert!(pattern("").unwrap().is_match(""));
+    assert!(pattern("^song").unwrap().is_match("Song1"));
+    assert!(!pattern("^song$").unwrap().is_match("Song1"));
+    assert!(pattern(r"@2\d0\b")
+        .unwrap()
+        .is_match("512@220 (8th notes break)"));
+    assert!(pattern("^(hard|challenge)$").unwrap().is_match("Hard"));
+    assert!(pattern("(").is_err());
+}
+
+#[test]
+fn test_x_axis() {
+    let mut chart = Chart::stream_unbroken(120., 4, 0);
+    chart.bpms = vec![(0., 120.), (4., 240.)];
+    assert_eq!(x(&chart, 3., XAxis::Time), 3.);
+    assert_eq!(x(&chart, 3., XAxis::Beats), 8.);
+    assert_eq!(x(&chart, 3., XAxis::Measures), 2.);
+    assert_eq!(Format::from_path(Path::new("a/b.SVG")), Some(Format::Svg));
+    assert_eq!(Format::from_path(Path::new("a/b")), None);
+    assert_eq!(Format::from_path(Path::new("b.jpg")), None);
+}
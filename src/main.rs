@@ -1,43 +1,320 @@
+mod calibrate;
 mod chart;
+mod crux;
+mod ensemble;
+mod features;
+mod graph;
+mod manifest;
+mod metrics;
+mod misrating;
+mod presets;
 mod rate;
 mod smparser;
+mod solve;
+mod timeline;
 mod train;
+mod weights;
 
 use chart::Chart;
 use clap::{Parser, Subcommand};
-use rate::{rate, Params};
-use std::collections::HashSet;
-use std::path::PathBuf;
+use rate::constants;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 struct Args {
     #[arg(help = "Paths of/directories containing .sm files", global = true)]
     inputs: Vec<PathBuf>,
 
+    #[arg(
+        help = "CSV file of path,difficulty,rating overrides, where rating may be \"exclude\"",
+        long = "manifest",
+        global = true
+    )]
+    manifest: Option<PathBuf>,
+
+    #[arg(
+        help = "Model name (with default params), model:p1,p2,... or params file to rate with, defaults to fatigue. Rates with the ensemble of every model given, or compares them",
+        short = 'm',
+        long = "model",
+        global = true
+    )]
+    models: Vec<String>,
+
+    #[arg(
+        help = "How to combine ensemble ratings",
+        long = "ensemble-combine",
+        value_enum,
+        default_value_t,
+        global = true
+    )]
+    ensemble_combine: ensemble::Combine,
+
     #[arg(help = "Use preset charts", short = 'p', global = true)]
     use_preset_charts: bool,
 
+    #[arg(
+        help = "CSV file of bpm,measures,rating[,break] preset charts to use instead of the built in ones, implies -p",
+        long = "presets",
+        global = true
+    )]
+    presets_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Synthetic chart to add as SPEC[=RATING], e.g. \"32@180 (8) 8ths:16 64@200=17\", where RATING is required to train, compare or find misrated charts",
+        long = "synthetic",
+        global = true
+    )]
+    synthetic: Vec<String>,
+
+    #[arg(
+        help = "Also describe each rated chart as the unbroken stream it rates like, e.g. 256@190, which rates the chart many more times",
+        long = "equivalent-stream",
+        global = true
+    )]
+    equivalent_stream: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    Train {
+    Train(TrainArgs),
+    Graph(GraphArgs),
+    // compares the models given by --model, or every model with default params
+    Compare,
+    Misrated {
+        #[arg(
+            help = "Report charts whose suggested meter differs from their meter by more than this",
+            short = 't',
+            long = "threshold",
+            default_value = "0"
+        )]
+        threshold: i32,
+    },
+    Solve(SolveArgs),
+    Export {
+        #[arg(
+            help = "Directory to write a song directory with an .sm file to for each preset or synthetic chart",
+            short = 'o'
+        )]
+        directory: PathBuf,
+    },
+    Rerate {
+        #[arg(
+            help = "Rewrite the meters, backing up each file to .bak (or .bak.1 and so on if that exists), instead of only showing the changes. Only .sm #NOTES meters are rewritten, .ssc files aren't read",
+            long = "apply"
+        )]
+        apply: bool,
+    },
+    Timeline {
         #[arg(
-            help = "Iterations to perform gradient descent",
-            short = 'i',
-            long = "gradient-descent-iterations",
-            default_value = "999"
+            help = "Directory to write a timeline file to for each chart",
+            short = 'o'
         )]
-        gradient_descent_iterations: i32,
+        directory: PathBuf,
+
+        #[arg(
+            help = "Timeline file format",
+            long = "format",
+            value_enum,
+            default_value_t
+        )]
+        format: timeline::Format,
     },
-    Graph {
-        #[arg(help = "Output graph path", short = 'o')]
-        graph_path: PathBuf,
+    Crux {
+        #[arg(help = "Print a JSON array instead of text", long = "json")]
+        json: bool,
     },
 }
 
+impl Command {
+    // Whether the command compares ratings against meters, so every chart needs one.
+    fn uses_meters(&self) -> bool {
+        matches!(
+            self,
+            Command::Train(_) | Command::Compare | Command::Misrated { .. }
+        )
+    }
+}
+
+#[derive(clap::Args)]
+struct GraphArgs {
+    #[arg(help = "Output graph path, or directory with --per-chart", short = 'o')]
+    graph_path: PathBuf,
+
+    #[arg(
+        help = "Only graph charts whose title matches this case insensitive regex",
+        long = "title",
+        value_parser = graph::pattern
+    )]
+    title: Option<regex::Regex>,
+
+    #[arg(
+        help = "Only graph charts whose difficulty matches this case insensitive regex",
+        long = "difficulty",
+        value_parser = graph::pattern
+    )]
+    difficulty: Option<regex::Regex>,
+
+    #[arg(help = "Graph against", long = "x-axis", value_enum, default_value_t)]
+    x_axis: graph::XAxis,
+
+    #[arg(
+        help = "Output format, defaults to the output path's extension or png",
+        long = "format",
+        value_enum
+    )]
+    format: Option<graph::Format>,
+
+    #[arg(
+        help = "Width in pixels, or hundredths of an inch for pdf",
+        long = "width",
+        default_value = "1280"
+    )]
+    width: u32,
+
+    #[arg(
+        help = "Height in pixels, or hundredths of an inch for pdf",
+        long = "height",
+        default_value = "720"
+    )]
+    height: u32,
+
+    #[arg(
+        help = "Draw a graph per chart into the output directory",
+        long = "per-chart"
+    )]
+    per_chart: bool,
+
+    #[arg(
+        help = "Graph every preset chart instead of only the longest ones",
+        long = "all-presets"
+    )]
+    all_presets: bool,
+}
+
+#[derive(clap::Args)]
+struct SolveArgs {
+    #[arg(
+        help = "Comma separated meters to solve for",
+        long = "meter",
+        value_delimiter = ',',
+        required = true
+    )]
+    meters: Vec<i32>,
+
+    #[arg(
+        help = "Comma separated BPMs to find the measures of unbroken stream needed for each meter",
+        long = "bpm",
+        value_delimiter = ','
+    )]
+    bpms: Vec<f64>,
+
+    #[arg(
+        help = "Comma separated measures of unbroken stream to find the BPM needed for each meter",
+        long = "measures",
+        value_delimiter = ','
+    )]
+    measures: Vec<i32>,
+}
+
+#[derive(clap::Args)]
+struct TrainArgs {
+    #[arg(
+        help = "Iterations to perform gradient descent",
+        short = 'i',
+        long = "gradient-descent-iterations",
+        default_value = "999"
+    )]
+    gradient_descent_iterations: i32,
+
+    #[arg(
+        help = "Loss function to minimize",
+        short = 'l',
+        long = "loss",
+        value_enum,
+        default_value_t
+    )]
+    loss: train::Loss,
+
+    #[arg(
+        help = "How to reweight charts by how common their meter or pack is",
+        long = "weighting",
+        value_enum,
+        default_value_t
+    )]
+    weighting: train::Weighting,
+
+    #[arg(
+        help = "CSV file of path,difficulty,weight[,confidence] per-chart weights",
+        long = "weights"
+    )]
+    weights_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Periodically save training progress to this path",
+        long = "checkpoint"
+    )]
+    checkpoint_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Iterations between checkpoints",
+        long = "checkpoint-every",
+        default_value = "10",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    checkpoint_every: u32,
+
+    #[arg(
+        help = "Resume training from a checkpoint, which is also updated unless --checkpoint is given",
+        long = "resume"
+    )]
+    resume_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Fraction of charts held out to report validation error",
+        long = "validation-fraction",
+        default_value = "0",
+        value_parser = train::validation_fraction
+    )]
+    validation_fraction: f64,
+
+    #[arg(
+        help = "Append per-iteration training history to this CSV file",
+        long = "history"
+    )]
+    history_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Plot the training loss curve to this PNG file",
+        long = "plot-history"
+    )]
+    history_graph_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Save the trained model and params to this file",
+        long = "save-params"
+    )]
+    save_params_path: Option<PathBuf>,
+
+    #[arg(
+        help = "Also fit this many bootstrap resamples of the charts to report how stable the params and ratings are",
+        long = "bootstrap",
+        default_value = "0"
+    )]
+    bootstrap_samples: usize,
+
+    #[arg(
+        help = "Map model output to meters with a mapping fit after training, training the model through an affine fit unless none; the ordinal loss uses its learned thresholds instead",
+        long = "calibration",
+        value_enum,
+        default_value_t
+    )]
+    calibration: calibrate::Kind,
+}
+
 fn sm_files_impl(path: &PathBuf, set: &mut HashSet<PathBuf>) {
     let metadata = std::fs::metadata(path).expect("couldn't get metadata for path");
     if metadata.is_file() {
@@ -63,51 +340,367 @@ fn sm_files(paths: &[PathBuf]) -> Vec<PathBuf> {
 
 fn charts(
     sm_files: &[PathBuf],
-    preset_charts: bool,
+    manifest: Option<&PathBuf>,
+    preset_charts: Option<&str>,
     only_longest_preset_charts: bool,
+    synthetic: &[String],
 ) -> Vec<Chart> {
     let mut charts = Vec::new();
     for sm_file in sm_files {
-        println!("Reading {:?}", sm_file);
+        eprintln!("Reading {:?}", sm_file);
         let buf = std::fs::read(sm_file).unwrap();
         let str = std::str::from_utf8(&buf).unwrap();
-        for chart in smparser::parse(str) {
+        for mut chart in smparser::parse(str) {
+            chart.path = Some(sm_file.clone());
             charts.push(chart);
         }
     }
-    if preset_charts {
-        charts.append(&mut Chart::presets(only_longest_preset_charts));
+    if let Some(manifest) = manifest {
+        let buf = std::fs::read_to_string(manifest).expect("couldn't read manifest");
+        let entries = manifest::parse(&buf).expect("couldn't parse manifest");
+        manifest::apply(&entries, &mut charts);
+    }
+    if let Some(buf) = preset_charts {
+        let mut presets = presets::parse(buf).expect("couldn't parse presets");
+        if only_longest_preset_charts {
+            presets.retain(|c| c.notes.len() == 512 * 16);
+        }
+        charts.append(&mut presets);
+    }
+    for synthetic in synthetic {
+        let (spec, rating) = match synthetic.rsplit_once('=') {
+            Some((spec, rating)) => (
+                spec,
+                rating.trim().parse::<i32>().unwrap_or_else(|_| {
+                    println!("Invalid rating in synthetic chart {synthetic:?}");
+                    std::process::exit(1);
+                }),
+            ),
+            None => (synthetic.as_str(), 0),
+        };
+        let Some(chart) = Chart::from_spec(spec, rating) else {
+            std::process::exit(1);
+        };
+        charts.push(chart);
     }
     charts
 }
 
-fn graph_fatigues(path: &PathBuf, charts: &[(&Chart, &Vec<(f64, f64)>)]) {
-    use gnuplot::*;
+// Model, params and calibration from a model name, a model name with comma
+// separated params as `model:p1,p2,...`, or a params file.
+fn model_and_params(spec: &str) -> (Box<dyn rate::RatingModel>, Vec<f64>, calibrate::Calibration) {
+    if let Some(model) = rate::model(spec) {
+        let params = model.default_params();
+        return (model, params, calibrate::Calibration::Identity);
+    }
+    if let Some((name, params)) = spec.split_once(':') {
+        if let Some(model) = rate::model(name) {
+            let Ok(params) = params
+                .split(',')
+                .map(|p| p.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+            else {
+                println!("invalid params in {spec}");
+                std::process::exit(1);
+            };
+            if params.len() != model.param_names().len() {
+                println!(
+                    "{spec} has {} params, but {name} expects {}",
+                    params.len(),
+                    model.param_names().len()
+                );
+                std::process::exit(1);
+            }
+            return (model, params, calibrate::Calibration::Identity);
+        }
+    }
+    rate::read_params(Path::new(spec)).unwrap_or_else(|| {
+        println!(
+            "{spec} is neither a model ({}) nor a params file",
+            rate::model_names().join(", ")
+        );
+        std::process::exit(1);
+    })
+}
+
+// Models, params and calibrations from every --model, defaulting to the fatigue
+// model.
+fn args_models(args: &Args) -> Vec<(Box<dyn rate::RatingModel>, Vec<f64>, calibrate::Calibration)> {
+    if args.models.is_empty() {
+        vec![model_and_params("fatigue")]
+    } else {
+        args.models.iter().map(|s| model_and_params(s)).collect()
+    }
+}
+
+// The only --model, for commands that can't use an ensemble.
+fn args_model(args: &Args) -> (Box<dyn rate::RatingModel>, Vec<f64>, calibrate::Calibration) {
+    let mut models = args_models(args);
+    if models.len() > 1 {
+        println!("only one --model can be given to this command");
+        std::process::exit(1);
+    }
+    models.pop().unwrap()
+}
+
+fn compare(specs: &[String], charts: &[Chart]) {
+    let specs = if specs.is_empty() {
+        rate::model_names().iter().map(|m| m.to_string()).collect()
+    } else {
+        specs.to_vec()
+    };
+    let meters = charts.iter().map(|c| c.rating).collect::<Vec<_>>();
+    let mut by_meter = meters.clone();
+    by_meter.sort();
+    by_meter.dedup();
+
+    let mut summaries = Vec::new();
+    for spec in &specs {
+        let (model, params, calibration) = model_and_params(spec);
+        let params = constants(&params);
+        let predictions = charts
+            .iter()
+            .map(|c| calibration.apply(model.rate(c, &params).0.value()))
+            .collect::<Vec<_>>();
+        let per_meter = by_meter
+            .iter()
+            .map(|meter| {
+                let (p, m): (Vec<f64>, Vec<i32>) = predictions
+                    .iter()
+                    .zip(&meters)
+                    .filter(|(_, m)| *m == meter)
+                    .unzip();
+                (*meter, metrics::summarize(&p, &m))
+            })
+            .collect::<Vec<_>>();
+        summaries.push((spec, metrics::summarize(&predictions, &meters), per_meter));
+    }
+
+    println!(
+        "{:24} {:>7} {:>7} {:>9} {:>8} {:>9}",
+        "model", "MSE", "MAE", "spearman", "kendall", "within 1"
+    );
+    for (spec, summary, _) in &summaries {
+        println!(
+            "{:24} {:>7.3} {:>7.3} {:>9.3} {:>8.3} {:>8.1}%",
+            spec,
+            summary.mse,
+            summary.mae,
+            summary.spearman,
+            summary.kendall,
+            summary.within_one * 100.
+        );
+    }
+    for (spec, _, per_meter) in &summaries {
+        println!();
+        println!("{spec} by meter:");
+        println!(
+            "{:>5} {:>6} {:>7} {:>7} {:>9}",
+            "meter", "charts", "MSE", "MAE", "within 1"
+        );
+        for (meter, summary) in per_meter {
+            println!(
+                "{:>5} {:>6} {:>7.3} {:>7.3} {:>8.1}%",
+                meter,
+                summary.charts,
+                summary.mse,
+                summary.mae,
+                summary.within_one * 100.
+            );
+        }
+    }
+}
+
+fn solve(
+    args: &SolveArgs,
+    model: &dyn rate::RatingModel,
+    params: &[f64],
+    calibration: &calibrate::Calibration,
+) {
+    let params = constants(params);
+    let solver = solve::Solver {
+        model,
+        params: &params,
+        calibration,
+    };
+    let header = |first: &str| {
+        print!("{first:>8}");
+        for meter in &args.meters {
+            print!(" {meter:>6}");
+        }
+        println!();
+    };
+    if !args.bpms.is_empty() {
+        println!("measures of unbroken stream for each meter:");
+        header("bpm");
+        for bpm in &args.bpms {
+            print!("{bpm:>8}");
+            for meter in &args.meters {
+                match solver.measures_for(*bpm, *meter) {
+                    Some(measures) => print!(" {measures:>6}"),
+                    None => print!(" {:>6}", "-"),
+                }
+            }
+            println!();
+        }
+    }
+    if !args.measures.is_empty() {
+        println!("BPM of unbroken stream for each meter:");
+        header("measures");
+        for measures in &args.measures {
+            print!("{measures:>8}");
+            for meter in &args.meters {
+                match solver.bpm_for(*measures, *meter) {
+                    Some(bpm) => print!(" {bpm:>6.1}"),
+                    None => print!(" {:>6}", "-"),
+                }
+            }
+            println!();
+        }
+    }
+}
+
+// `name` with characters that may not be allowed in file names replaced.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "@-_() ".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// File name without extension for output about a chart, as "pack - description".
+fn chart_file_name(chart: &Chart) -> String {
+    let name = file_name(&chart.description());
+    if chart.pack().is_empty() {
+        name
+    } else {
+        format!("{} - {name}", file_name(&chart.pack()))
+    }
+}
 
-    let mut fg = gnuplot::Figure::new();
-    let a = fg
-        .axes2d()
-        .set_x_label("time", &[])
-        .set_y_label("fatigue", &[]);
-    for (chart, fatigue_times) in charts {
-        let times = fatigue_times.iter().map(|(a, _)| *a).collect::<Vec<_>>();
-        let fatigues = fatigue_times.iter().map(|(_, a)| *a).collect::<Vec<_>>();
-        let caption = chart.description().replace('@', "\\@");
-        a.points(times, fatigues, &[PlotOption::Caption(&caption)]);
+fn export(directory: &Path, charts: &[Chart]) {
+    for chart in charts.iter().filter(|c| c.path.is_none()) {
+        let name = file_name(&chart.title);
+        let song_directory = directory.join(&name);
+        std::fs::create_dir_all(&song_directory).expect("couldn't create song directory");
+        let path = song_directory.join(format!("{name}.sm"));
+        std::fs::write(&path, smparser::write_chart(chart)).expect("couldn't write .sm");
+        println!("wrote {:?}", path);
+    }
+}
+
+// Writes `buf` to the first of `path`.bak, `path`.bak.1, `path`.bak.2, ... that
+// doesn't exist yet, so earlier backups are never overwritten.
+fn backup(path: &Path, buf: &str) -> PathBuf {
+    use std::io::Write;
+    for i in 0.. {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        if i > 0 {
+            backup.push(format!(".{i}"));
+        }
+        let backup = PathBuf::from(backup);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+        {
+            Ok(mut file) => {
+                file.write_all(buf.as_bytes())
+                    .expect("couldn't write backup");
+                return backup;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => panic!("couldn't write backup: {e}"),
+        }
+    }
+    unreachable!()
+}
+
+// Rewrites the meter of each chart in its simfile to its suggested meter,
+// showing each change as a diff. Only .sm files are read, so this only rewrites
+// #NOTES meters and never the #METER tags of .ssc files.
+fn rerate(ratings: &[(&Chart, f64)], apply: bool) {
+    let mut suggested = HashMap::<&PathBuf, HashMap<String, i32>>::new();
+    for (chart, rating) in ratings {
+        if let Some(path) = &chart.path {
+            suggested
+                .entry(path)
+                .or_default()
+                .insert(chart.description(), calibrate::suggested_meter(*rating));
+        }
+    }
+    let mut paths = suggested.keys().copied().collect::<Vec<_>>();
+    paths.sort();
+    for path in paths {
+        let buf = std::fs::read_to_string(path).expect("couldn't read simfile");
+        let mut simfile = smparser::Simfile::parse(&buf).expect("couldn't parse simfile");
+        for (i, chart) in simfile.charts().iter().enumerate() {
+            if let Some(meter) = suggested[path].get(&chart.description()) {
+                if *meter != chart.rating {
+                    simfile.set_meter(i, *meter);
+                }
+            }
+        }
+        let rerated = simfile.write();
+        if rerated == buf {
+            continue;
+        }
+        println!("--- {}", path.display());
+        println!("+++ {}", path.display());
+        // changing meters never adds or removes lines
+        for (i, (old, new)) in buf.lines().zip(rerated.lines()).enumerate() {
+            if old != new {
+                println!("@@ line {} @@", i + 1);
+                println!("-{old}");
+                println!("+{new}");
+            }
+        }
+        if apply {
+            let backup = backup(path, &buf);
+            println!("backed up to {}", backup.display());
+            std::fs::write(path, rerated).expect("couldn't write simfile");
+        }
+    }
+    if !apply {
+        println!("dry run, pass --apply to rewrite meters");
     }
-    fg.save_to_png(path, 1280, 720).unwrap();
-    println!("drew fatigue graph to {:?}", path);
 }
 
 fn main() {
     let args = Args::parse();
 
+    if let Command::Solve(solve_args) = &args.command {
+        let (model, params, calibration) = args_model(&args);
+        solve(solve_args, model.as_ref(), &params, &calibration);
+        return;
+    }
+
+    if args.command.uses_meters() {
+        if let Some(spec) = args.synthetic.iter().find(|s| !s.contains('=')) {
+            println!("Synthetic chart {spec:?} needs a =RATING for this command");
+            std::process::exit(1);
+        }
+    }
+
     let sm_files = sm_files(&args.inputs);
 
-    let charts = charts(
+    let presets = match &args.presets_path {
+        Some(path) => Some(std::fs::read_to_string(path).expect("couldn't read presets")),
+        None if args.use_preset_charts => Some(presets::DEFAULT.to_owned()),
+        None => None,
+    };
+    let mut charts = charts(
         &sm_files,
-        args.use_preset_charts,
-        matches!(args.command, Command::Graph { graph_path: _ }),
+        args.manifest.as_ref(),
+        presets.as_deref(),
+        matches!(&args.command, Command::Graph(graph_args) if !graph_args.all_presets),
+        &args.synthetic,
     );
 
     if charts.is_empty() {
@@ -115,37 +708,281 @@ fn main() {
         std::process::exit(1);
     }
 
-    let mut params = Params::default();
+    if let Command::Graph(graph_args) = &args.command {
+        charts.retain(|c| {
+            graph_args
+                .title
+                .as_ref()
+                .is_none_or(|p| p.is_match(&c.title))
+                && graph_args
+                    .difficulty
+                    .as_ref()
+                    .is_none_or(|p| p.is_match(&c.difficulty))
+        });
+        if charts.is_empty() {
+            println!("No charts match the title and difficulty patterns");
+            std::process::exit(1);
+        }
+    }
+
+    if let Command::Export { directory } = &args.command {
+        export(directory, &charts);
+        return;
+    }
 
-    if let Command::Train {
-        gradient_descent_iterations,
-    } = args.command
-    {
-        let err;
-        (params, err) = train::train(&charts, params, gradient_descent_iterations);
+    if let Command::Compare = &args.command {
+        compare(&args.models, &charts);
+        return;
+    }
+
+    let mut models = args_models(&args);
+    let mut bootstrap_fits = Vec::new();
+    if let Command::Train(train_args) = &args.command {
+        let (model, params, _) = args_model(&args);
+        if let Some(weights_path) = &train_args.weights_path {
+            let buf = std::fs::read_to_string(weights_path).expect("couldn't read weights file");
+            let entries = weights::parse(&buf).expect("couldn't parse weights file");
+            weights::apply(&entries, &mut charts);
+        }
+        if matches!(train_args.loss, train::Loss::Ranking)
+            && train_args.calibration == calibrate::Kind::None
+        {
+            println!(
+                "--loss ranking needs --calibration affine or isotonic to map ratings to meters"
+            );
+            std::process::exit(1);
+        }
+        let options = train::Options {
+            max_iterations: train_args.gradient_descent_iterations,
+            loss: train_args.loss,
+            weighting: train_args.weighting,
+            checkpoint_path: train_args
+                .checkpoint_path
+                .clone()
+                .or(train_args.resume_path.clone()),
+            checkpoint_every: train_args.checkpoint_every,
+            resume: train_args.resume_path.as_ref().map(|p| {
+                train::Checkpoint::read(p).expect("couldn't read checkpoint to resume from")
+            }),
+            validation_fraction: train_args.validation_fraction,
+            calibration: train_args.calibration,
+        };
+        let trained = train::train(model.as_ref(), &charts, &params, &options);
+        let params = trained.params;
+        let calibration = trained.calibration;
+        if let Some(save_params_path) = &train_args.save_params_path {
+            rate::write_params(save_params_path, model.as_ref(), &params, &calibration);
+        }
+        if let Some(history_path) = &train_args.history_path {
+            train::write_history(history_path, &trained.history);
+        }
+        if let Some(history_graph_path) = &train_args.history_graph_path {
+            graph::history(history_graph_path, &trained.history);
+        }
         println!("-------------");
-        println!("params: {:?}", params.to_vec());
-        println!("err: {err}");
+        for (name, param) in model.param_names().iter().zip(&params) {
+            println!("{name}: {param}");
+        }
+        println!(
+            "params: {}",
+            params
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        println!("calibration: {calibration}");
+        println!("err: {}", trained.train_err);
+        if let Some(validation_err) = trained.validation_err {
+            println!("validation err: {validation_err}");
+        }
+        if train_args.bootstrap_samples > 0 {
+            bootstrap_fits = train::bootstrap(
+                model.as_ref(),
+                &charts,
+                &params,
+                &options,
+                train_args.bootstrap_samples,
+            );
+            println!("-------------");
+            println!("bootstrap params:");
+            for (i, name) in model.param_names().iter().enumerate() {
+                let values = bootstrap_fits
+                    .iter()
+                    .map(|t| t.params[i])
+                    .collect::<Vec<_>>();
+                let p = ensemble::combine(&values, ensemble::Combine::Mean);
+                println!("{name}: {} ±{} [{}, {}]", p.rating, p.stddev, p.low, p.high);
+            }
+        }
+        models = vec![(model, params, calibration)];
     }
 
+    // with --bootstrap, every bootstrap fit gives the interval around the main
+    // fit's rating
+    let intervals = (!bootstrap_fits.is_empty()).then(|| {
+        let name = models[0].0.name();
+        ensemble::Ensemble::new(
+            bootstrap_fits
+                .into_iter()
+                .map(|t| (rate::model(name).unwrap(), t.params, t.calibration))
+                .collect(),
+            args.ensemble_combine,
+        )
+    });
+    let ensemble = ensemble::Ensemble::new(models, args.ensemble_combine);
     let mut ratings = Vec::new();
     for chart in charts {
-        let (rating, fatigues) = rate(&chart, params);
-        ratings.push((chart, rating.value(), fatigues));
+        let (mut prediction, fatigues) = ensemble.rate(&chart);
+        if let Some(intervals) = &intervals {
+            prediction = ensemble::Prediction {
+                rating: prediction.rating,
+                ..intervals.rate(&chart).0
+            };
+        }
+        ratings.push((chart, prediction, fatigues));
     }
-    ratings.sort_by(|(_, r1, _), (_, r2, _)| r1.total_cmp(r2));
+    ratings.sort_by(|(_, r1, _), (_, r2, _)| r1.rating.total_cmp(&r2.rating));
 
-    if let Command::Graph { graph_path } = args.command {
+    if let Command::Graph(graph_args) = &args.command {
+        let path = &graph_args.graph_path;
+        let format = graph_args.format.or_else(|| {
+            if graph_args.per_chart {
+                None
+            } else {
+                graph::Format::from_path(path)
+            }
+        });
+        let options = graph::Options {
+            x_axis: graph_args.x_axis,
+            format: format.unwrap_or(graph::Format::Png),
+            width: graph_args.width,
+            height: graph_args.height,
+        };
+        let y_label = ensemble.members[0].0.name();
         let mapped = ratings.iter().map(|(a, _, c)| (a, c)).collect::<Vec<_>>();
-        graph_fatigues(&graph_path, &mapped);
+        if graph_args.per_chart {
+            std::fs::create_dir_all(path).expect("couldn't create graph directory");
+            for chart in &mapped {
+                let chart_path = path.join(format!(
+                    "{}.{}",
+                    chart_file_name(chart.0),
+                    options.format.extension()
+                ));
+                graph::fatigues(&chart_path, y_label, &[*chart], &options);
+            }
+        } else {
+            graph::fatigues(path, y_label, &mapped, &options);
+        }
+    }
+
+    if let Command::Timeline { directory, format } = &args.command {
+        std::fs::create_dir_all(directory).expect("couldn't create timeline directory");
+        let extension = match format {
+            timeline::Format::Csv => "csv",
+            timeline::Format::Json => "json",
+        };
+        for (chart, _, states) in &ratings {
+            let path = directory.join(format!("{}.{extension}", chart_file_name(chart)));
+            // skip the state before the first note
+            let points = timeline::timeline(chart, &states[1..]);
+            std::fs::write(&path, timeline::write(chart, &points, *format))
+                .expect("couldn't write timeline");
+            println!("wrote {:?}", path);
+        }
+        return;
+    }
+
+    if let Command::Crux { json } = args.command {
+        let mut objects = Vec::new();
+        for (chart, prediction, states) in &ratings {
+            let Some(crux) = crux::crux(chart, &timeline::timeline(chart, &states[1..])) else {
+                continue;
+            };
+            if json {
+                objects.push(crux.to_json(chart));
+            } else {
+                println!(
+                    "{:>5.2}: {:2}, {} - {}",
+                    prediction.rating,
+                    chart.rating,
+                    chart.description(),
+                    crux.description()
+                );
+            }
+        }
+        if json {
+            println!("[{}]", objects.join(",\n"));
+        }
+        return;
+    }
+
+    if let Command::Rerate { apply } = args.command {
+        let ratings = ratings
+            .iter()
+            .map(|(chart, prediction, _)| (chart, prediction.rating))
+            .collect::<Vec<_>>();
+        rerate(&ratings, apply);
+        return;
+    }
+
+    if let Command::Misrated { threshold } = args.command {
+        let ratings = ratings
+            .iter()
+            .map(|(chart, prediction, _)| (chart, prediction.rating))
+            .collect::<Vec<_>>();
+        for ((pack, stepartist), misratings) in misrating::report(&ratings, threshold) {
+            println!(
+                "{} by {}:",
+                if pack.is_empty() { "(no pack)" } else { &pack },
+                if stepartist.is_empty() {
+                    "(unknown)"
+                } else {
+                    &stepartist
+                }
+            );
+            for m in misratings {
+                println!(
+                    "  {:+3} {:>5.2}: {:2} (suggested {:2}) - {}",
+                    m.suggested - m.chart.rating,
+                    m.rating,
+                    m.chart.rating,
+                    m.suggested,
+                    m.chart.description()
+                );
+            }
+        }
+        return;
     }
 
-    for (chart, rating, _) in ratings {
+    for (chart, prediction, _) in ratings {
+        let spread = if ensemble.members.len() > 1 || intervals.is_some() {
+            format!(
+                " ±{:.2} [{:>5.2}, {:>5.2}]",
+                prediction.stddev, prediction.low, prediction.high
+            )
+        } else {
+            String::new()
+        };
+        let equivalent = if args.equivalent_stream {
+            // in terms of the first member like the graphed states
+            let (model, params, _) = &ensemble.members[0];
+            let stream = match rate::equivalent_bpm(model.as_ref(), &chart, params) {
+                Some((bpm, measures)) => format!("{measures}@{bpm:.0}"),
+                None => "?".to_owned(),
+            };
+            format!(", like {stream:>8}")
+        } else {
+            String::new()
+        };
         println!(
-            "{:>5.2}: {:2}, {:6} notes - {}",
-            rating,
+            "{:>5.2}{}: {:2} (suggested {:2}), {:6} notes{} - {}",
+            prediction.rating,
+            spread,
             chart.rating,
+            calibrate::suggested_meter(prediction.rating),
             chart.notes.len(),
+            equivalent,
             chart.description(),
         );
     }
@@ -0,0 +1,112 @@
+use crate::chart::Chart;
+use std::path::PathBuf;
+
+#[derive(PartialEq, Debug)]
+pub enum Override {
+    Rating(i32),
+    Exclude,
+}
+
+// One line of a manifest: `path,difficulty,rating` where rating is either the
+// corrected meter or "exclude".
+#[derive(PartialEq, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub difficulty: String,
+    pub rating: Override,
+}
+
+// Non-empty, non-comment lines of a comma separated file with their trimmed
+// fields.
+pub fn lines(buf: &str) -> impl Iterator<Item = (&str, Vec<&str>)> {
+    buf.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| (line, line.split(',').map(|f| f.trim()).collect()))
+}
+
+pub fn parse(buf: &str) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (line, fields) in lines(buf) {
+        if fields.len() != 3 {
+            println!("Expected path,difficulty,rating: {line}");
+            return None;
+        }
+        let rating = if fields[2].eq_ignore_ascii_case("exclude") {
+            Override::Exclude
+        } else {
+            match fields[2].parse::<i32>() {
+                Ok(r) => Override::Rating(r),
+                Err(_) => {
+                    println!("Invalid rating: {line}");
+                    return None;
+                }
+            }
+        };
+        entries.push(Entry {
+            path: PathBuf::from(fields[0]),
+            difficulty: fields[1].to_owned(),
+            rating,
+        });
+    }
+    Some(entries)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse(""), Some(vec![]));
+    assert_eq!(parse("# comment\n\n"), Some(vec![]));
+    assert_eq!(
+        parse("pack/song, Hard ,14\npack/other/other.sm,Challenge,EXCLUDE"),
+        Some(vec![
+            Entry {
+                path: "pack/song".into(),
+                difficulty: "Hard".into(),
+                rating: Override::Rating(14),
+            },
+            Entry {
+                path: "pack/other/other.sm".into(),
+                difficulty: "Challenge".into(),
+                rating: Override::Exclude,
+            },
+        ])
+    );
+    assert_eq!(parse("pack/song,Hard"), None);
+    assert_eq!(parse("pack/song,Hard,hard"), None);
+}
+
+// Replaces ratings of charts listed in the manifest and drops excluded charts.
+// Later entries take precedence over earlier ones.
+pub fn apply(entries: &[Entry], charts: &mut Vec<Chart>) {
+    charts.retain_mut(|chart| {
+        let mut keep = true;
+        for entry in entries {
+            if chart.matches(&entry.path, &entry.difficulty) {
+                match entry.rating {
+                    Override::Rating(r) => {
+                        chart.rating = r;
+                        keep = true;
+                    }
+                    Override::Exclude => keep = false,
+                }
+            }
+        }
+        keep
+    });
+}
+
+#[test]
+fn test_apply() {
+    let mut charts = vec![
+        Chart::stream_unbroken(200., 16, 15),
+        Chart::stream_unbroken(200., 32, 16),
+        Chart::stream_unbroken(200., 64, 17),
+    ];
+    charts[0].path = Some("/songs/pack/a/a.sm".into());
+    charts[1].path = Some("/songs/pack/b/b.sm".into());
+    let entries = parse("pack/a,,14\npack/b,,exclude").unwrap();
+    apply(&entries, &mut charts);
+    assert_eq!(charts.len(), 2);
+    assert_eq!(charts[0].rating, 14);
+    assert_eq!(charts[1].rating, 17);
+}
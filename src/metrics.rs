@@ -0,0 +1,128 @@
+use crate::calibrate::suggested_meter;
+
+// Ranks of `values` starting from 1, with ties getting the average of their ranks.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order = (0..values.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.;
+        for i in &order[start..end] {
+            ranks[*i] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let mut cov = 0.;
+    let mut var_a = 0.;
+    let mut var_b = 0.;
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+pub fn spearman(a: &[f64], b: &[f64]) -> f64 {
+    pearson(&ranks(a), &ranks(b))
+}
+
+// Kendall's tau-b, which accounts for ties.
+pub fn kendall(a: &[f64], b: &[f64]) -> f64 {
+    let mut concordant = 0f64;
+    let mut discordant = 0.;
+    let mut ties_a = 0.;
+    let mut ties_b = 0.;
+    for i in 0..a.len() {
+        for j in i + 1..a.len() {
+            let da = a[i] - a[j];
+            let db = b[i] - b[j];
+            if da == 0. && db == 0. {
+                continue;
+            } else if da == 0. {
+                ties_a += 1.;
+            } else if db == 0. {
+                ties_b += 1.;
+            } else if (da > 0.) == (db > 0.) {
+                concordant += 1.;
+            } else {
+                discordant += 1.;
+            }
+        }
+    }
+    (concordant - discordant)
+        / ((concordant + discordant + ties_a) * (concordant + discordant + ties_b)).sqrt()
+}
+
+// How well predicted ratings match meters.
+pub struct Summary {
+    pub charts: usize,
+    pub mse: f64,
+    pub mae: f64,
+    pub spearman: f64,
+    pub kendall: f64,
+    // fraction of charts whose suggested meter is within 1 of their meter
+    pub within_one: f64,
+}
+
+// `predictions` are on the same scale as the training targets, where a chart
+// with meter m should be rated in [m, m + 1).
+pub fn summarize(predictions: &[f64], meters: &[i32]) -> Summary {
+    let targets = meters.iter().map(|m| *m as f64 + 0.5).collect::<Vec<_>>();
+    let n = predictions.len() as f64;
+    let errors = predictions.iter().zip(&targets).map(|(p, t)| p - t);
+    Summary {
+        charts: predictions.len(),
+        mse: errors.clone().map(|e| e * e).sum::<f64>() / n,
+        mae: errors.map(|e| e.abs()).sum::<f64>() / n,
+        spearman: spearman(predictions, &targets),
+        kendall: kendall(predictions, &targets),
+        within_one: predictions
+            .iter()
+            .zip(meters)
+            .filter(|(p, m)| (suggested_meter(**p) - **m).abs() <= 1)
+            .count() as f64
+            / n,
+    }
+}
+
+#[test]
+fn test_ranks() {
+    assert_eq!(ranks(&[]), Vec::<f64>::new());
+    assert_eq!(ranks(&[3., 1., 2.]), vec![3., 1., 2.]);
+    assert_eq!(ranks(&[3., 1., 3., 0.]), vec![3.5, 2., 3.5, 1.]);
+}
+
+#[test]
+fn test_correlations() {
+    let a = [1., 2., 3., 4.];
+    assert_eq!(spearman(&a, &[10., 20., 30., 40.]), 1.);
+    assert_eq!(spearman(&a, &[4., 3., 2., 1.]), -1.);
+    assert_eq!(kendall(&a, &[1., 4., 9., 16.]), 1.);
+    assert_eq!(kendall(&a, &[4., 3., 2., 1.]), -1.);
+    // one discordant pair out of six
+    assert_eq!(kendall(&a, &[1., 3., 2., 4.]), 4. / 6.);
+}
+
+#[test]
+fn test_summarize() {
+    let summary = summarize(&[15.5, 16., 18.5], &[15, 16, 17]);
+    assert_eq!(summary.charts, 3);
+    assert_eq!(summary.mse, (0. + 0.25 + 1.) / 3.);
+    assert_eq!(summary.mae, (0. + 0.5 + 1.) / 3.);
+    assert_eq!(summary.within_one, 1.);
+    let summary = summarize(&[15.5, 18.], &[15, 16]);
+    assert_eq!(summary.within_one, 0.5);
+}
@@ -0,0 +1,81 @@
+use crate::calibrate::suggested_meter;
+use crate::chart::Chart;
+use std::collections::HashMap;
+
+// A chart whose suggested meter differs from its declared meter.
+pub struct Misrating<'a> {
+    pub chart: &'a Chart,
+    pub rating: f64,
+    pub suggested: i32,
+}
+
+impl Misrating<'_> {
+    // Distance of the rating from the middle of the declared meter.
+    pub fn severity(&self) -> f64 {
+        (self.rating - (self.chart.rating as f64 + 0.5)).abs()
+    }
+}
+
+// Charts whose suggested meter is more than `threshold` meters from their declared
+// meter, grouped by (pack, stepartist). Groups are ordered by their most severe
+// misrating, and charts within a group by severity.
+pub fn report<'a>(
+    ratings: &[(&'a Chart, f64)],
+    threshold: i32,
+) -> Vec<((String, String), Vec<Misrating<'a>>)> {
+    let mut groups = HashMap::<(String, String), Vec<Misrating>>::new();
+    for (chart, rating) in ratings {
+        let suggested = suggested_meter(*rating);
+        if (suggested - chart.rating).abs() > threshold {
+            groups
+                .entry((chart.pack(), chart.stepartist.clone()))
+                .or_default()
+                .push(Misrating {
+                    chart,
+                    rating: *rating,
+                    suggested,
+                });
+        }
+    }
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    for (_, misratings) in &mut groups {
+        misratings.sort_by(|a, b| b.severity().total_cmp(&a.severity()));
+    }
+    groups.sort_by(|(_, a), (_, b)| b[0].severity().total_cmp(&a[0].severity()));
+    groups
+}
+
+#[test]
+fn test_report() {
+    let chart = |pack: &str, stepartist: &str, rating: i32| {
+        let mut chart = Chart::stream_unbroken(120., 1, rating);
+        chart.path = Some(format!("{pack}/song/song.sm").into());
+        chart.stepartist = stepartist.to_owned();
+        chart
+    };
+    let charts = [
+        chart("a", "x", 10),
+        chart("a", "x", 10),
+        chart("a", "y", 10),
+        chart("b", "x", 10),
+    ];
+    let ratings = [
+        (&charts[0], 11.2),
+        (&charts[1], 13.9),
+        (&charts[2], 10.9),
+        (&charts[3], 7.5),
+    ];
+    let groups = report(&ratings, 0);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].0, ("a".to_owned(), "x".to_owned()));
+    assert_eq!(
+        groups[0].1.iter().map(|m| m.suggested).collect::<Vec<_>>(),
+        vec![13, 11]
+    );
+    assert_eq!(groups[1].0, ("b".to_owned(), "x".to_owned()));
+    assert_eq!(groups[1].1[0].severity(), 3.);
+    // only the charts off by 3 meters are off by more than 2
+    let groups = report(&ratings, 2);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].1.len(), 1);
+}
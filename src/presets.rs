@@ -0,0 +1,55 @@
+use crate::chart::Chart;
+
+// Presets used unless another file is given.
+pub const DEFAULT: &str = include_str!("presets.csv");
+
+// Parses preset charts from lines of `bpm,measures,rating[,break]`, where break is
+// "arrowless" or "8ths".
+pub fn parse(buf: &str) -> Option<Vec<Chart>> {
+    let mut charts = Vec::new();
+    for (line, fields) in crate::manifest::lines(buf) {
+        if fields.len() != 3 && fields.len() != 4 {
+            println!("Expected bpm,measures,rating[,break]: {line}");
+            return None;
+        }
+        let (Ok(bpm), Ok(measures), Ok(rating)) = (
+            fields[0].parse::<f64>(),
+            fields[1].parse::<i32>(),
+            fields[2].parse::<i32>(),
+        ) else {
+            println!("Invalid number: {line}");
+            return None;
+        };
+        let chart = match fields.get(3) {
+            None => Chart::stream_unbroken(bpm, measures, rating),
+            Some(b) if b.eq_ignore_ascii_case("arrowless") => {
+                Chart::stream_with_arrowless_break(bpm, measures, rating)
+            }
+            Some(b) if b.eq_ignore_ascii_case("8ths") => {
+                Chart::stream_with_8ths_break(bpm, measures, rating)
+            }
+            Some(_) => {
+                println!("Invalid break, expected arrowless or 8ths: {line}");
+                return None;
+            }
+        };
+        charts.push(chart);
+    }
+    Some(charts)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse("# comment\n\n").unwrap().len(), 0);
+    let charts = parse("170, 96, 15\n180,64,15,arrowless\n180,64,16,8THS").unwrap();
+    assert_eq!(charts.len(), 3);
+    assert_eq!(charts[0].description(), "96@170");
+    assert_eq!(charts[0].rating, 15);
+    assert_eq!(charts[1].rating, 15);
+    assert_eq!(charts[1].notes.len(), 2 * 64 * 16);
+    assert_eq!(charts[2].notes.len(), 2 * 64 * 16 + 64 * 8);
+    assert!(parse("170,96").is_none());
+    assert!(parse("170,96,x").is_none());
+    assert!(parse("170,96,15,24ths").is_none());
+    assert_eq!(parse(DEFAULT).unwrap().len(), 57);
+}
@@ -1,74 +1,311 @@
+use crate::calibrate::Calibration;
 use crate::chart::Chart;
+use crate::features::{cached_peak_nps, LinearFeatures, PEAK_NPS_WINDOWS};
+use crate::solve::bpm_reaching;
 use autodiff::{Float, Zero, F1};
+use std::path::Path;
 
-#[derive(Clone, Copy, Debug)]
-pub struct Params {
-    pub step_dt_mult: F1,
-    pub step_dt_add: F1,
-    pub ratio_dt_mult: F1,
+// State of a model while stepping through the notes of a chart.
+pub trait ModelState {
+    fn step(&mut self, time: f64);
+    // Value graphed over time, e.g. the current fatigue.
+    fn current(&self) -> f64;
+    // Rating of the chart, valid after stepping through all notes.
+    fn score(&self) -> F1;
 }
 
-impl Params {
-    pub fn new(step_dt_mult: f64, step_dt_add: f64, ratio_dt_mult: f64) -> Self {
-        Self {
-            step_dt_mult: F1::cst(step_dt_mult),
-            step_dt_add: F1::cst(step_dt_add),
-            ratio_dt_mult: F1::cst(ratio_dt_mult),
+pub trait RatingModel {
+    fn name(&self) -> &'static str;
+    fn param_names(&self) -> Vec<String>;
+    // Inclusive (min, max) of each param, enforced while training.
+    fn param_bounds(&self) -> Vec<(f64, f64)>;
+    fn default_params(&self) -> Vec<f64>;
+    fn state<'a>(&self, chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a>;
+
+    // Penalty added to the training error, e.g. to keep weights small.
+    fn regularization(&self, _params: &[F1]) -> F1 {
+        F1::cst(0.)
+    }
+
+    // Returns the rating of the chart and the model's state after each note.
+    fn rate(&self, chart: &Chart, params: &[F1]) -> (F1, Vec<(f64, f64)>) {
+        assert_eq!(params.len(), self.param_names().len());
+        let mut state = self.state(chart, params);
+        let mut states = Vec::with_capacity(chart.notes.len() + 1);
+        states.push((0.0, 0.0));
+        for note in &chart.notes {
+            state.step(note.time);
+            states.push((note.time, state.current()))
+        }
+        (state.score(), states)
+    }
+}
+
+// Every model, looked up by the name it reports. Adding one here makes it available
+// to --model.
+const MODELS: [fn() -> Box<dyn RatingModel>; 6] = [
+    || Box::new(Fatigue),
+    || {
+        Box::new(MultiTimescaleFatigue {
+            name: "multi-timescale",
+            timescales: 2,
+        })
+    },
+    || {
+        Box::new(MultiTimescaleFatigue {
+            name: "multi-timescale-3",
+            timescales: 3,
+        })
+    },
+    || Box::new(RecoveryFatigue),
+    || Box::new(LinearFeatures),
+    || Box::new(PeakDensity),
+];
+
+pub fn model_names() -> Vec<&'static str> {
+    MODELS.iter().map(|m| m().name()).collect()
+}
+
+pub fn model(name: &str) -> Option<Box<dyn RatingModel>> {
+    MODELS.iter().map(|m| m()).find(|m| m.name() == name)
+}
+
+// Writes the model name, params and calibration to be read back by `read_params`.
+pub fn write_params(
+    path: &Path,
+    model: &dyn RatingModel,
+    params: &[f64],
+    calibration: &Calibration,
+) {
+    let params = params
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    std::fs::write(
+        path,
+        format!(
+            "model={}\nparams={}\ncalibration={}\n",
+            model.name(),
+            params,
+            calibration
+        ),
+    )
+    .expect("couldn't write params");
+}
+
+// Reads a model, params and calibration written by `write_params`, or the best
+// params of a training checkpoint, which are uncalibrated.
+pub fn read_params(path: &Path) -> Option<(Box<dyn RatingModel>, Vec<f64>, Calibration)> {
+    let buf = std::fs::read_to_string(path).ok()?;
+    let mut model_name = None;
+    let mut params = None;
+    let mut calibration = Some(Calibration::Identity);
+    for line in buf.lines() {
+        match line.split_once('=') {
+            Some(("model", name)) => model_name = Some(name.trim()),
+            Some(("best_params", v)) => params = Some(v),
+            Some(("params", v)) if params.is_none() => params = Some(v),
+            Some(("calibration", c)) => calibration = Calibration::parse(c.trim()),
+            _ => {}
         }
     }
+    let model = model(model_name?)?;
+    let params = params?
+        .split(',')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if params.len() != model.param_names().len() {
+        return None;
+    }
+    Some((model, params, calibration?))
+}
+
+pub fn constants(v: &[f64]) -> Vec<F1> {
+    v.iter().map(|x| F1::cst(*x)).collect()
+}
+
+// BPM at which an unbroken stream of 16ths with as many notes as `chart` rates the
+// same as it, with the stream's measures, e.g. to describe a chart as 256 measures
+// at 190. Assumes ratings increase with BPM, and is None if no BPM in a plausible
+// range matches.
+pub fn equivalent_bpm(model: &dyn RatingModel, chart: &Chart, params: &[F1]) -> Option<(f64, i32)> {
+    let measures = chart.notes.len().div_ceil(16).max(1) as i32;
+    let target = model.rate(chart, params).0.x;
+    let bpm = bpm_reaching(target, |bpm| {
+        model
+            .rate(&Chart::stream_unbroken(bpm, measures, 0), params)
+            .0
+            .x
+    })?;
+    Some((bpm, measures))
+}
 
-    pub fn to_vec(self) -> Vec<f64> {
+// Fatigue decays exponentially over time and each note adds more fatigue the
+// closer it is to the previous note. Rates a chart by its peak fatigue.
+pub struct Fatigue;
+
+impl RatingModel for Fatigue {
+    fn name(&self) -> &'static str {
+        "fatigue"
+    }
+
+    fn param_names(&self) -> Vec<String> {
         vec![
-            self.step_dt_mult.value(),
-            self.step_dt_add.value(),
-            self.ratio_dt_mult.value(),
+            "step_dt_mult".to_owned(),
+            "step_dt_add".to_owned(),
+            "ratio_dt_mult".to_owned(),
         ]
     }
 
-    pub fn from_vec(v: &[f64]) -> Self {
-        Self::new(v[0], v[1], v[2])
+    fn param_bounds(&self) -> Vec<(f64, f64)> {
+        vec![(0., f64::INFINITY); 3]
     }
-}
 
-impl Default for Params {
-    fn default() -> Self {
-        Self::new(1.6725047878328008, 22.69176212395888, 0.03094850290834286)
+    fn default_params(&self) -> Vec<f64> {
+        vec![1.6725047878328008, 22.69176212395888, 0.03094850290834286]
+    }
+
+    fn state<'a>(&self, _chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a> {
+        Box::new(FatigueState {
+            accumulator: Accumulator::new(params),
+            max_fatigue: F1::cst(0.0),
+            last_time: 0.,
+        })
     }
 }
 
-struct State {
-    cur_fatigue: F1,
-    max_fatigue: F1,
-    last_time: f64,
-    params: Params,
+// Fatigue that decays exponentially with time and grows with each note.
+struct Accumulator {
+    fatigue: F1,
+    step_dt_mult: F1,
+    step_dt_add: F1,
+    ratio_dt_mult: F1,
 }
 
-impl State {
-    fn with_params(params: Params) -> Self {
+impl Accumulator {
+    // Takes the params step_dt_mult, step_dt_add, ratio_dt_mult.
+    fn new(params: &[F1]) -> Self {
         Self {
-            cur_fatigue: F1::cst(0.0),
-            max_fatigue: F1::cst(0.0),
-            last_time: 0.,
-            params,
+            fatigue: F1::cst(0.0),
+            step_dt_mult: params[0],
+            step_dt_add: params[1],
+            ratio_dt_mult: params[2],
         }
     }
-    fn step(&mut self, time: f64) {
-        let dt = F1::cst(time - self.last_time);
-        assert!(dt.value() >= 0.);
 
+    fn step(&mut self, dt: F1) {
         let ratio = if dt.is_zero() {
             F1::cst(1.0)
         } else {
-            (-self.params.ratio_dt_mult * dt).exp()
+            (-self.ratio_dt_mult * dt).exp()
         };
 
         if ratio.value() < 0.0 || ratio.value() > 1.0 {
             panic!("unexpected ratio: {}, dt {}", ratio, dt);
         }
 
-        self.cur_fatigue *= ratio;
-        self.cur_fatigue +=
-            F1::cst(1.0) / (dt * self.params.step_dt_mult + self.params.step_dt_add);
+        self.fatigue *= ratio;
+        self.fatigue += F1::cst(1.0) / (dt * self.step_dt_mult + self.step_dt_add);
+    }
+}
+
+struct FatigueState {
+    accumulator: Accumulator,
+    max_fatigue: F1,
+    last_time: f64,
+}
+
+impl ModelState for FatigueState {
+    fn step(&mut self, time: f64) {
+        let dt = F1::cst(time - self.last_time);
+        assert!(dt.value() >= 0.);
+
+        self.accumulator.step(dt);
+
+        if self.accumulator.fatigue > self.max_fatigue {
+            self.max_fatigue = self.accumulator.fatigue;
+        }
+
+        self.last_time = time;
+    }
+
+    fn current(&self) -> f64 {
+        self.accumulator.fatigue.value()
+    }
+
+    fn score(&self) -> F1 {
+        self.max_fatigue
+    }
+}
+
+// Several fatigue accumulators decaying at different rates, e.g. a quickly
+// recovering one for short bursts and a slowly recovering one for stamina.
+// Rates a chart by the peak of their sum.
+pub struct MultiTimescaleFatigue {
+    name: &'static str,
+    timescales: usize,
+}
+
+impl RatingModel for MultiTimescaleFatigue {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        (0..self.timescales)
+            .flat_map(|i| {
+                Fatigue
+                    .param_names()
+                    .into_iter()
+                    .map(move |name| format!("{name}_{i}"))
+            })
+            .collect()
+    }
+
+    fn param_bounds(&self) -> Vec<(f64, f64)> {
+        vec![(0., f64::INFINITY); 3 * self.timescales]
+    }
+
+    // The first accumulator starts as the single timescale fatigue model and each
+    // following one recovers 4x faster but contributes 4x less.
+    fn default_params(&self) -> Vec<f64> {
+        let fatigue = Fatigue.default_params();
+        (0..self.timescales)
+            .flat_map(|i| {
+                let scale = 4f64.powi(i as i32);
+                [fatigue[0], fatigue[1] * scale, fatigue[2] * scale]
+            })
+            .collect()
+    }
+
+    fn state<'a>(&self, _chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a> {
+        Box::new(MultiTimescaleFatigueState {
+            accumulators: params.chunks(3).map(Accumulator::new).collect(),
+            cur_fatigue: F1::cst(0.0),
+            max_fatigue: F1::cst(0.0),
+            last_time: 0.,
+        })
+    }
+}
+
+struct MultiTimescaleFatigueState {
+    accumulators: Vec<Accumulator>,
+    cur_fatigue: F1,
+    max_fatigue: F1,
+    last_time: f64,
+}
+
+impl ModelState for MultiTimescaleFatigueState {
+    fn step(&mut self, time: f64) {
+        let dt = F1::cst(time - self.last_time);
+        assert!(dt.value() >= 0.);
+
+        self.cur_fatigue = F1::cst(0.0);
+        for accumulator in &mut self.accumulators {
+            accumulator.step(dt);
+            self.cur_fatigue += accumulator.fatigue;
+        }
 
         if self.cur_fatigue > self.max_fatigue {
             self.max_fatigue = self.cur_fatigue;
@@ -76,36 +313,288 @@ impl State {
 
         self.last_time = time;
     }
+
+    fn current(&self) -> f64 {
+        self.cur_fatigue.value()
+    }
+
+    fn score(&self) -> F1 {
+        self.max_fatigue
+    }
+}
+
+// Like the fatigue model, but breaks longer than a learned threshold recover
+// fatigue faster the longer they are, on top of the usual exponential decay.
+// Training the recovery params needs rated charts with breaks, which the default
+// presets don't have yet.
+pub struct RecoveryFatigue;
+
+impl RatingModel for RecoveryFatigue {
+    fn name(&self) -> &'static str {
+        "recovery"
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        let mut names = Fatigue.param_names();
+        names.push("recovery_threshold".to_owned());
+        names.push("recovery_rate".to_owned());
+        names
+    }
+
+    fn param_bounds(&self) -> Vec<(f64, f64)> {
+        vec![(0., f64::INFINITY); 5]
+    }
+
+    // Starts with recovery kicking in after a half second gap, which is
+    // roughly an 8th note at 60 BPM.
+    fn default_params(&self) -> Vec<f64> {
+        let mut params = Fatigue.default_params();
+        params.push(0.5);
+        params.push(0.01);
+        params
+    }
+
+    fn state<'a>(&self, _chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a> {
+        Box::new(RecoveryFatigueState {
+            accumulator: Accumulator::new(params),
+            recovery_threshold: params[3],
+            recovery_rate: params[4],
+            max_fatigue: F1::cst(0.0),
+            last_time: 0.,
+        })
+    }
+}
+
+struct RecoveryFatigueState {
+    accumulator: Accumulator,
+    recovery_threshold: F1,
+    recovery_rate: F1,
+    max_fatigue: F1,
+    last_time: f64,
+}
+
+impl ModelState for RecoveryFatigueState {
+    fn step(&mut self, time: f64) {
+        let dt = F1::cst(time - self.last_time);
+        assert!(dt.value() >= 0.);
+
+        let rest = dt - self.recovery_threshold;
+        if rest.value() > 0.0 {
+            self.accumulator.fatigue *= (-self.recovery_rate * rest * rest).exp();
+        }
+        self.accumulator.step(dt);
+
+        if self.accumulator.fatigue > self.max_fatigue {
+            self.max_fatigue = self.accumulator.fatigue;
+        }
+
+        self.last_time = time;
+    }
+
+    fn current(&self) -> f64 {
+        self.accumulator.fatigue.value()
+    }
+
+    fn score(&self) -> F1 {
+        self.max_fatigue
+    }
+}
+
+// Baseline rating a chart by its peak notes per second over a few window
+// lengths: bias + sum of weight * peak NPS for each window.
+pub struct PeakDensity;
+
+impl RatingModel for PeakDensity {
+    fn name(&self) -> &'static str {
+        "nps"
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        let mut names = vec!["bias".to_owned()];
+        for window in PEAK_NPS_WINDOWS {
+            names.push(format!("nps_{window}s"));
+        }
+        names
+    }
+
+    fn param_bounds(&self) -> Vec<(f64, f64)> {
+        let mut bounds = vec![(f64::NEG_INFINITY, f64::INFINITY)];
+        bounds.extend([(0., f64::INFINITY); PEAK_NPS_WINDOWS.len()]);
+        bounds
+    }
+
+    // Roughly rates 16ths at 200 BPM as a 16.
+    fn default_params(&self) -> Vec<f64> {
+        vec![0., 0., 1.2, 0.]
+    }
+
+    fn state<'a>(&self, chart: &'a Chart, params: &'a [F1]) -> Box<dyn ModelState + 'a> {
+        let mut rating = params[0];
+        for (nps, weight) in cached_peak_nps(chart).into_iter().zip(&params[1..]) {
+            rating += *weight * F1::cst(nps);
+        }
+        Box::new(PeakDensityState { rating })
+    }
 }
 
-pub fn rate(chart: &Chart, params: Params) -> (F1, Vec<(f64, f64)>) {
-    let mut fatigue = State::with_params(params);
-    let mut fatigues = Vec::with_capacity(chart.notes.len());
-    fatigues.push((0.0, 0.0));
-    for note in &chart.notes {
-        fatigue.step(note.time);
-        fatigues.push((note.time, fatigue.cur_fatigue.value()))
+struct PeakDensityState {
+    rating: F1,
+}
+
+impl ModelState for PeakDensityState {
+    fn step(&mut self, _time: f64) {}
+
+    fn current(&self) -> f64 {
+        self.rating.x
+    }
+
+    fn score(&self) -> F1 {
+        self.rating
     }
-    (fatigue.max_fatigue, fatigues)
 }
 
 #[test]
 fn rate_sanity_tests() {
-    let params = Params::default();
-    assert!(
-        rate(&Chart::stream_with_arrowless_break(200., 16, 0), params)
-            < rate(&Chart::stream_with_8ths_break(200., 16, 0), params)
+    for name in [
+        "fatigue",
+        "multi-timescale",
+        "multi-timescale-3",
+        "recovery",
+    ] {
+        let model = model(name).unwrap();
+        let params = constants(&model.default_params());
+        let rate = |chart: &Chart| model.rate(chart, &params);
+        assert!(
+            rate(&Chart::stream_with_arrowless_break(200., 16, 0))
+                < rate(&Chart::stream_with_8ths_break(200., 16, 0))
+        );
+        assert!(
+            rate(&Chart::stream_unbroken(200., 16, 0))
+                < rate(&Chart::stream_with_arrowless_break(200., 16, 0))
+        );
+        assert!(
+            rate(&Chart::stream_unbroken(200., 16, 0)) < rate(&Chart::stream_unbroken(200., 17, 0))
+        );
+        assert!(
+            rate(&Chart::stream_unbroken(200., 16, 0)) < rate(&Chart::stream_unbroken(201., 16, 0))
+        );
+    }
+}
+
+#[test]
+fn test_models() {
+    let names = model_names();
+    for name in &names {
+        let model = model(name).unwrap();
+        assert_eq!(model.name(), *name);
+        let params = model.default_params();
+        assert_eq!(params.len(), model.param_names().len());
+        assert_eq!(params.len(), model.param_bounds().len());
+        for (p, (min, max)) in params.iter().zip(model.param_bounds()) {
+            assert!(min <= *p && *p <= max);
+        }
+    }
+    assert!(model("nope").is_none());
+    let mut unique = names.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), names.len());
+}
+
+#[test]
+fn test_multi_timescale_fatigue() {
+    // with a single timescale it's the same as the fatigue model
+    let model = MultiTimescaleFatigue {
+        name: "multi-timescale-1",
+        timescales: 1,
+    };
+    let params = constants(&Fatigue.default_params());
+    let chart = Chart::stream_with_8ths_break(180., 32, 0);
+    assert_eq!(
+        model.rate(&chart, &params).0,
+        Fatigue.rate(&chart, &params).0
     );
-    assert!(
-        rate(&Chart::stream_unbroken(200., 16, 0), params)
-            < rate(&Chart::stream_with_arrowless_break(200., 16, 0), params)
+
+    // a fast recovering accumulator separates a short burst from the same notes
+    // spread out with a break
+    let model = self::model("multi-timescale").unwrap();
+    let params = constants(&model.default_params());
+    let burst = Chart::stream_unbroken(230., 8, 0);
+    let spread = Chart::stream_with_arrowless_break(230., 4, 0);
+    assert!(model.rate(&spread, &params).0 < model.rate(&burst, &params).0);
+}
+
+#[test]
+fn test_recovery_fatigue() {
+    // gaps in 8ths or faster are all below the default recovery threshold, so
+    // unbroken stream rates the same as the fatigue model
+    let params = constants(&RecoveryFatigue.default_params());
+    let chart = Chart::stream_with_8ths_break(180., 32, 0);
+    assert_eq!(
+        RecoveryFatigue.rate(&chart, &params).0,
+        Fatigue.rate(&chart, &params[..3]).0
     );
-    assert!(
-        rate(&Chart::stream_unbroken(200., 16, 0), params)
-            < rate(&Chart::stream_unbroken(200., 17, 0), params)
+
+    let chart = Chart::stream_with_arrowless_break(180., 32, 0);
+    assert!(RecoveryFatigue.rate(&chart, &params).0 < Fatigue.rate(&chart, &params[..3]).0);
+}
+
+#[test]
+fn test_peak_density() {
+    let params = constants(&[1., 1., 10., 100.]);
+    // 2 measures of 16ths at 120 BPM are 32 notes over 4 seconds, filling the 2s
+    // window, half the 8s window and an eighth of the 32s window
+    let chart = Chart::stream_unbroken(120., 2, 0);
+    assert_eq!(
+        PeakDensity.rate(&chart, &params).0.value(),
+        1. + 8. + 10. * 32. / 8. + 100. * 32. / 32.
     );
+    let params = constants(&PeakDensity.default_params());
     assert!(
-        rate(&Chart::stream_unbroken(200., 16, 0), params)
-            < rate(&Chart::stream_unbroken(201., 16, 0), params)
+        PeakDensity.rate(&Chart::stream_unbroken(200., 16, 0), &params)
+            < PeakDensity.rate(&Chart::stream_unbroken(210., 16, 0), &params)
+    );
+}
+
+#[test]
+fn test_equivalent_bpm() {
+    let params = constants(&Fatigue.default_params());
+    let (bpm, measures) =
+        equivalent_bpm(&Fatigue, &Chart::stream_unbroken(190., 64, 0), &params).unwrap();
+    assert!((bpm - 190.).abs() < 0.01);
+    assert_eq!(measures, 64);
+    // the break makes it easier than the same notes unbroken at the same BPM
+    let chart = Chart::stream_with_8ths_break(200., 16, 0);
+    let (bpm, measures) = equivalent_bpm(&Fatigue, &chart, &params).unwrap();
+    assert!(bpm < 200.);
+    assert_eq!(measures, 40);
+}
+
+#[test]
+fn test_params_file() {
+    let path = std::env::temp_dir().join(format!("itgrate_params_{}", std::process::id()));
+    let calibration = Calibration::Affine {
+        scale: 2.,
+        offset: 1.,
+    };
+    write_params(
+        &path,
+        &RecoveryFatigue,
+        &[1., 2., 3., 4., 5.5],
+        &calibration,
     );
+    let (model, params, read_calibration) = read_params(&path).unwrap();
+    assert_eq!(model.name(), "recovery");
+    assert_eq!(params, vec![1., 2., 3., 4., 5.5]);
+    assert_eq!(read_calibration, calibration);
+    std::fs::write(&path, "model=fatigue\nparams=1,2\n").unwrap();
+    assert!(read_params(&path).is_none());
+    std::fs::write(
+        &path,
+        "model=fatigue\niteration=3\nparams=1,2,3\nbest_params=4,5,6\n",
+    )
+    .unwrap();
+    assert_eq!(read_params(&path).unwrap().1, vec![4., 5., 6.]);
+    assert_eq!(read_params(&path).unwrap().2, Calibration::Identity);
+    std::fs::remove_file(&path).unwrap();
 }
@@ -1,45 +1,147 @@
 use crate::chart::{Chart, Note};
 use std::collections::HashMap;
 
-fn parse_msd(buf: &str) -> Option<HashMap<String, Vec<String>>> {
-    let mut map = HashMap::<String, Vec<String>>::new();
-    let mut str = String::new();
-    for mut line in buf.lines() {
-        if let Some(comment) = line.find("//") {
-            line = &line[0..comment];
+// `buf` with comments replaced by spaces, so that offsets into it are offsets into
+// `buf` and separators inside comments are ignored.
+fn blank_comments(buf: &str) -> String {
+    let mut blanked = buf.as_bytes().to_vec();
+    let mut i = 0;
+    while i + 1 < blanked.len() {
+        if &blanked[i..i + 2] == b"//" {
+            while i < blanked.len() && blanked[i] != b'\n' {
+                blanked[i] = b' ';
+                i += 1;
+            }
+        } else {
+            i += 1;
         }
-        assert!(!line.contains('\\'), "found \\, can't handle escaping yet");
-        str.push_str(line);
-        str.push('\n');
-    }
-    if !str.is_empty() {
-        str.pop();
     }
+    String::from_utf8(blanked).unwrap()
+}
 
-    for split in str.split(';') {
-        let split = split.trim_start();
-        if split.is_empty() {
-            continue;
-        }
-        let c = split.chars().next().unwrap();
-        if c != '#' {
-            println!("No #: {split}");
-            return None;
+// `buf` with comments removed, up to but not including each line break, and
+// carriage returns before line breaks dropped.
+fn strip_comments(buf: &str) -> String {
+    buf.split('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            match line.find("//") {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Tag {
+    pub key: String,
+    // as written, including any comments
+    pub raw: String,
+    // text before the #, e.g. whitespace and comments
+    prefix: String,
+    // whether the tag ends with a ;
+    terminated: bool,
+}
+
+impl Tag {
+    // The value with comments removed.
+    pub fn value(&self) -> String {
+        let mut value = strip_comments(&self.raw);
+        // the line break ending the file isn't part of an unterminated last tag
+        if !self.terminated && value.ends_with('\n') {
+            value.pop();
         }
-        let colon = match split.find(':') {
-            Some(c) => c,
-            None => {
-                println!("No :: {split}");
+        value
+    }
+}
+
+// A .sm or .ssc file as its tags in order, including ones the parser doesn't
+// understand, along with the comments and whitespace around them so that it's
+// written back exactly as read apart from intended changes.
+#[derive(Debug, PartialEq)]
+pub struct Simfile {
+    pub tags: Vec<Tag>,
+    // text after the last tag
+    trailing: String,
+}
+
+impl Simfile {
+    pub fn parse(buf: &str) -> Option<Self> {
+        let blanked = blank_comments(buf);
+        assert!(
+            !blanked.contains('\\'),
+            "found \\, can't handle escaping yet"
+        );
+        let mut tags = Vec::new();
+        let mut prefix = String::new();
+        let mut offset = 0;
+        let num_splits = blanked.split(';').count();
+        for (i, split) in blanked.split(';').enumerate() {
+            let raw = &buf[offset..offset + split.len()];
+            offset += split.len() + 1;
+            let terminated = i + 1 < num_splits;
+            let start = split.trim_start_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+            if start.is_empty() {
+                prefix.push_str(raw);
+                if terminated {
+                    prefix.push(';');
+                }
+                continue;
+            }
+            if !start.starts_with('#') {
+                println!("No #: {}", strip_comments(raw).trim());
                 return None;
             }
-        };
+            let hash = split.len() - start.len();
+            let Some(colon) = start.find(':') else {
+                println!("No :: {}", strip_comments(raw).trim());
+                return None;
+            };
+            prefix.push_str(&raw[..hash]);
+            tags.push(Tag {
+                key: strip_comments(&raw[hash + 1..hash + colon]),
+                raw: raw[hash + colon + 1..].to_owned(),
+                prefix: std::mem::take(&mut prefix),
+                terminated,
+            });
+        }
+        Some(Self {
+            tags,
+            trailing: prefix,
+        })
+    }
 
-        let key = &split[1..colon];
-        let val = &split[(colon + 1)..split.len()];
+    pub fn write(&self) -> String {
+        let mut buf = String::new();
+        for tag in &self.tags {
+            buf.push_str(&tag.prefix);
+            buf.push('#');
+            buf.push_str(&tag.key);
+            buf.push(':');
+            buf.push_str(&tag.raw);
+            if tag.terminated {
+                buf.push(';');
+            }
+        }
+        buf.push_str(&self.trailing);
+        buf
+    }
 
-        map.entry(key.to_owned()).or_default().push(val.to_owned());
+    // Values of each tag by key, in order, with comments removed.
+    fn msd(&self) -> HashMap<String, Vec<String>> {
+        let mut map = HashMap::<String, Vec<String>>::new();
+        for tag in &self.tags {
+            map.entry(tag.key.clone()).or_default().push(tag.value());
+        }
+        map
     }
-    Some(map)
+}
+
+#[cfg(test)]
+fn parse_msd(buf: &str) -> Option<HashMap<String, Vec<String>>> {
+    Some(Simfile::parse(buf)?.msd())
 }
 
 #[test]
@@ -107,10 +209,10 @@ fn test_parse_msd() {
     );
 }
 
-fn split_notes(buf: &str) -> Option<(String, String, i32, String)> {
+fn split_notes(buf: &str) -> Option<(String, String, String, i32, String)> {
     let mut split = buf.split(':');
     let style = split.next()?.trim().to_owned();
-    split.next()?;
+    let stepartist = split.next()?.trim().to_owned();
     let difficulty = split.next()?.trim().to_owned();
     let rating = match split.next()?.trim().parse::<i32>() {
         Ok(r) => r,
@@ -124,7 +226,7 @@ fn split_notes(buf: &str) -> Option<(String, String, i32, String)> {
         return None;
     }
 
-    Some((style, difficulty, rating, steps))
+    Some((style, stepartist, difficulty, rating, steps))
 }
 
 #[test]
@@ -132,11 +234,11 @@ fn test_split_notes() {
     assert_eq!(split_notes(""), None);
     assert_eq!(
         split_notes("a:b:c:0:d:e"),
-        Some(("a".into(), "c".into(), 0, "e".into()))
+        Some(("a".into(), "b".into(), "c".into(), 0, "e".into()))
     );
     assert_eq!(
         split_notes("a:b: c: 2 :d:e"),
-        Some(("a".into(), "c".into(), 2, "e".into()))
+        Some(("a".into(), "b".into(), "c".into(), 2, "e".into()))
     );
     assert_eq!(split_notes("a:b:c:0:d"), None);
     assert_eq!(split_notes("a:b:c:z:d:e"), None);
@@ -269,14 +371,11 @@ fn parse_steps(buf: &str, bpms: &BPMs) -> Option<Vec<Note>> {
             .collect::<Vec<_>>();
         let measures_per_line = 1.0 / lines.len() as f64;
         for (line_num, line) in lines.iter().enumerate() {
-            for c in line.trim().chars() {
+            let mut arrows = 0;
+            for (column, c) in line.trim().chars().enumerate() {
                 match c {
                     '1' | '2' | '4' => {
-                        let cur_measure = line_num as f64 * measures_per_line + measure_num as f64;
-                        steps.push(Note {
-                            time: bpms.measure_to_time(cur_measure),
-                        });
-                        break;
+                        arrows |= 1 << column;
                     }
                     '0' | '3' | 'M' | 'L' | 'F' => {}
                     a => {
@@ -285,6 +384,13 @@ fn parse_steps(buf: &str, bpms: &BPMs) -> Option<Vec<Note>> {
                     }
                 }
             }
+            if arrows != 0 {
+                let cur_measure = line_num as f64 * measures_per_line + measure_num as f64;
+                steps.push(Note {
+                    time: bpms.measure_to_time(cur_measure),
+                    arrows,
+                });
+            }
         }
     }
 
@@ -301,63 +407,288 @@ fn test_parse_steps() {
     assert_eq!(parse_steps(" 00  ", &bpms), Some(vec![]));
     assert_eq!(parse_steps("3M", &bpms), Some(vec![]));
     assert_eq!(parse_steps("05", &bpms), None);
-    assert_eq!(parse_steps("10", &bpms), Some(vec![Note { time: 0. }]));
-    assert_eq!(parse_steps("00\n10", &bpms), Some(vec![Note { time: 2. }]));
-    assert_eq!(parse_steps("11", &bpms), Some(vec![Note { time: 0. }]));
+    assert_eq!(
+        parse_steps("10", &bpms),
+        Some(vec![Note {
+            time: 0.,
+            arrows: 0b01
+        }])
+    );
+    assert_eq!(
+        parse_steps("00\n10", &bpms),
+        Some(vec![Note {
+            time: 2.,
+            arrows: 0b01
+        }])
+    );
+    assert_eq!(
+        parse_steps("11", &bpms),
+        Some(vec![Note {
+            time: 0.,
+            arrows: 0b11
+        }])
+    );
+    assert_eq!(
+        parse_steps("0M24", &bpms),
+        Some(vec![Note {
+            time: 0.,
+            arrows: 0b1100
+        }])
+    );
     assert_eq!(
         parse_steps("00\n,\n10", &bpms),
-        Some(vec![Note { time: 4. }])
+        Some(vec![Note {
+            time: 4.,
+            arrows: 0b01
+        }])
     );
     assert_eq!(
         parse_steps("00\n,\n10\n10", &bpms),
-        Some(vec![Note { time: 4. }, Note { time: 6. }])
+        Some(vec![
+            Note {
+                time: 4.,
+                arrows: 0b01
+            },
+            Note {
+                time: 6.,
+                arrows: 0b01
+            }
+        ])
     );
     assert_eq!(
         parse_steps("00\n,\n10\n,\n10\n,\n10\n", &bpms),
         Some(vec![
-            Note { time: 4. },
-            Note { time: 8. },
-            Note { time: 10. }
+            Note {
+                time: 4.,
+                arrows: 0b01
+            },
+            Note {
+                time: 8.,
+                arrows: 0b01
+            },
+            Note {
+                time: 10.,
+                arrows: 0b01
+            }
         ])
     );
 }
 
 pub fn parse(buf: &str) -> Vec<Chart> {
-    let mut charts = Vec::new();
-    // Strip BOM
-    let buf = buf.trim_start_matches('\u{feff}');
-    let msd = parse_msd(buf).unwrap();
-    if let Some(stops) = msd.get("STOPS") {
-        for stop in stops {
-            if !stop.trim().is_empty() {
-                return vec![];
+    Simfile::parse(buf).unwrap().charts()
+}
+
+// Whether `parse` returns charts of this style and difficulty.
+fn is_rated(style: &str, difficulty: &str) -> bool {
+    style == "dance-single" && difficulty != "Edit"
+}
+
+impl Simfile {
+    // Indices of the #NOTES tags of the charts `charts` returns, in the same order.
+    fn chart_tags(&self) -> Vec<usize> {
+        self.tags
+            .iter()
+            .enumerate()
+            .filter(|(_, tag)| tag.key == "NOTES")
+            .filter(|(_, tag)| {
+                split_notes(&tag.value())
+                    .is_some_and(|(style, _, difficulty, _, _)| is_rated(&style, &difficulty))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Sets the meter of the `chart`th chart `charts` returns, keeping the whitespace
+    // and comments around it.
+    pub fn set_meter(&mut self, chart: usize, meter: i32) {
+        let index = self.chart_tags()[chart];
+        let tag = &mut self.tags[index];
+        let blanked = blank_comments(&tag.raw);
+        // style, stepartist and difficulty come before the meter
+        let start = blanked
+            .match_indices(':')
+            .nth(2)
+            .expect("#NOTES without a meter")
+            .0
+            + 1;
+        let end = start + blanked[start..].find(':').expect("#NOTES without a meter");
+        let field = &blanked[start..end];
+        let meter_start = start + field.len() - field.trim_start().len();
+        let meter_end = meter_start + field.trim().len();
+        tag.raw
+            .replace_range(meter_start..meter_end, &meter.to_string());
+    }
+
+    pub fn charts(&self) -> Vec<Chart> {
+        let mut charts = Vec::new();
+        let msd = self.msd();
+        if let Some(stops) = msd.get("STOPS") {
+            for stop in stops {
+                if !stop.trim().is_empty() {
+                    return vec![];
+                }
+            }
+        }
+        let title = &msd.get("TITLE").unwrap()[0];
+        let bpms = {
+            let bpms = &msd.get("BPMS").unwrap();
+            assert_eq!(bpms.len(), 1);
+            parse_bpms(&bpms[0]).unwrap()
+        };
+        if let Some(all_notes) = msd.get("NOTES") {
+            let song_charts = all_notes
+                .iter()
+                .map(|n| split_notes(n).unwrap())
+                .filter(|(style, _, difficulty, _, _)| is_rated(style, difficulty))
+                .collect::<Vec<_>>();
+            for (_, stepartist, difficulty, rating, steps) in &song_charts {
+                charts.push(Chart {
+                    title: title.clone(),
+                    difficulty: difficulty.clone(),
+                    only_chart: song_charts.len() == 1,
+                    stepartist: stepartist.clone(),
+                    notes: parse_steps(steps, &bpms).unwrap(),
+                    rating: *rating,
+                    bpms: bpms.bpm_changes.clone(),
+                    path: None,
+                    weight: 1.0,
+                    confidence: 1.0,
+                    features: std::cell::OnceCell::new(),
+                });
             }
         }
+        charts
     }
-    let title = &msd.get("TITLE").unwrap()[0];
-    let bpms = {
-        let bpms = &msd.get("BPMS").unwrap();
-        assert_eq!(bpms.len(), 1);
-        parse_bpms(&bpms[0]).unwrap()
+}
+
+// Writes a chart as a playable .sm file with a single dance-single chart. Rows
+// are quantized to 192nds of a measure, using the coarsest quantization that
+// fits each measure.
+pub fn write_chart(chart: &Chart) -> String {
+    const ROWS_PER_MEASURE: [usize; 10] = [4, 8, 12, 16, 24, 32, 48, 64, 96, 192];
+    let mut measures = Vec::<Vec<(usize, u8)>>::new();
+    for note in &chart.notes {
+        let row = (chart.time_to_beat(note.time) * 48.).round() as usize;
+        let measure = row / 192;
+        if measures.len() <= measure {
+            measures.resize(measure + 1, Vec::new());
+        }
+        measures[measure].push((row % 192, note.arrows));
+    }
+    if measures.is_empty() {
+        measures.push(Vec::new());
+    }
+    let steps = measures
+        .iter()
+        .map(|notes| {
+            let rows = ROWS_PER_MEASURE
+                .into_iter()
+                .find(|rows| notes.iter().all(|(row, _)| row % (192 / rows) == 0))
+                .unwrap();
+            let mut lines = vec![[b'0'; 4]; rows];
+            for (row, arrows) in notes {
+                for (column, c) in lines[row / (192 / rows)].iter_mut().enumerate() {
+                    if arrows & (1 << column) != 0 {
+                        *c = b'1';
+                    }
+                }
+            }
+            lines
+                .iter()
+                .map(|l| std::str::from_utf8(l).unwrap().to_owned() + "\n")
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    let bpms = chart
+        .bpms
+        .iter()
+        .map(|(beat, bpm)| format!("{beat}={bpm}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let difficulty = if chart.difficulty.is_empty() {
+        "Challenge"
+    } else {
+        &chart.difficulty
     };
-    if let Some(all_notes) = msd.get("NOTES") {
-        let song_charts = all_notes
-            .iter()
-            .map(|n| split_notes(n).unwrap())
-            .filter(|(style, difficulty, _, _)| style == "dance-single" && difficulty != "Edit")
-            .collect::<Vec<_>>();
-        for (_, difficulty, rating, steps) in &song_charts {
-            charts.push(Chart {
-                title: title.clone(),
-                difficulty: if song_charts.len() > 1 {
-                    difficulty.clone()
-                } else {
-                    "".to_owned()
-                },
-                notes: parse_steps(steps, &bpms).unwrap(),
-                rating: *rating,
-            });
+    format!(
+        "#TITLE:{};\n#OFFSET:0;\n#BPMS:{};\n#STOPS:;\n#NOTES:\n     dance-single:\n     {}:\n     {}:\n     {}:\n     0,0,0,0,0:\n{};\n",
+        msd_value(&chart.title),
+        bpms,
+        msd_value(&chart.stepartist),
+        difficulty,
+        chart.rating,
+        steps
+    )
+}
+
+// `value` with the characters that separate tags and values, and the escape
+// character `parse` can't handle, replaced so it can be written as a value.
+fn msd_value(value: &str) -> String {
+    value.replace([':', ';', '#', '\\'], "_")
+}
+
+#[test]
+fn test_write_chart() {
+    for chart in [
+        Chart::stream_with_8ths_break(190., 4, 15),
+        Chart::from_spec("2@120 (1) 24ths:1 1@60 32nds:1@150", 12).unwrap(),
+    ] {
+        let parsed = parse(&write_chart(&chart));
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].title, msd_value(&chart.title));
+        assert_eq!(parsed[0].rating, chart.rating);
+        assert_eq!(parsed[0].bpms, chart.bpms);
+        assert_eq!(parsed[0].notes.len(), chart.notes.len());
+        for (a, b) in parsed[0].notes.iter().zip(&chart.notes) {
+            assert!((a.time - b.time).abs() < 1e-9);
+            assert_eq!(a.arrows, b.arrows);
         }
     }
-    charts
+    let sm = write_chart(&Chart::stream_unbroken(120., 1, 10));
+    assert!(sm.contains(":\n1000\n0100\n0010\n0001\n1000\n"));
+    let sm = write_chart(&Chart::from_spec("32@180 8ths:16 64@200", 12).unwrap());
+    assert_eq!(sm.lines().next(), Some("#TITLE:32@180 8ths_16 64@200;"));
+    assert_eq!(msd_value("a:b;c#d\\e"), "a_b_c_d_e");
+}
+
+#[test]
+fn test_simfile_round_trip() {
+    let sm = "\u{feff}#TITLE:Song;\n// comment; with #TAG:\n#BANNER:banner.png; // trailing\r\n#BPMS:0=120;\n#CUSTOM:a:b;;\n#NOTES:\n     dance-single:\n     Author:\n     Hard:\n     9:\n     0,0,0,0,0:\n1000\n0100 // hands\n0010\n0001\n;\n#NOTES:\n     dance-double:\n     :\n     Hard:\n     9:\n     :\n10000000\n;\n";
+    let simfile = Simfile::parse(sm).unwrap();
+    assert_eq!(
+        simfile
+            .tags
+            .iter()
+            .map(|t| t.key.as_str())
+            .collect::<Vec<_>>(),
+        vec!["TITLE", "BANNER", "BPMS", "CUSTOM", "NOTES", "NOTES"]
+    );
+    assert_eq!(simfile.tags[3].value(), "a:b");
+    assert!(simfile.tags[4].value().contains("0100 \n"));
+    assert_eq!(simfile.write(), sm);
+    let charts = simfile.charts();
+    assert_eq!(charts.len(), 1);
+    assert_eq!(charts[0].stepartist, "Author");
+    assert_eq!(charts[0].notes.len(), 4);
+
+    let ssc = "#VERSION:0.83;\n#TITLE:Song;\n#NOTEDATA:;\n#STEPSTYPE:dance-single;\n#METER:9;\n#NOTES:\n1000\n;\n#NOTEDATA:;\n#METER:10";
+    let simfile = Simfile::parse(ssc).unwrap();
+    assert_eq!(simfile.tags.len(), 8);
+    assert_eq!(simfile.write(), ssc);
+    assert_eq!(Simfile::parse("#A:;\nB:;"), None);
+}
+
+#[test]
+fn test_set_meter() {
+    let sm = "#TITLE:Song;\n#BPMS:0=120;\n// #NOTES:dance-single::Hard:1:;\n#NOTES:\n     dance-single:\n     Author:\n     Hard:\n     9 :\n     0,0,0,0,0:\n1000\n;\n#NOTES:dance-double::Hard:8::10000000;\n#NOTES:\n     dance-single: // a; b:\n     :\n     Challenge:\n     12:\n     :\n0001\n;\n";
+    let mut simfile = Simfile::parse(sm).unwrap();
+    simfile.set_meter(0, 10);
+    simfile.set_meter(1, 13);
+    let rerated = simfile.write();
+    assert_eq!(
+        parse(&rerated).iter().map(|c| c.rating).collect::<Vec<_>>(),
+        vec![10, 13]
+    );
+    assert_eq!(rerated.replace("10 :", "9 :").replace("13:", "12:"), sm);
 }
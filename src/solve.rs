@@ -0,0 +1,91 @@
+use crate::calibrate::Calibration;
+use crate::chart::Chart;
+use crate::rate::RatingModel;
+use autodiff::F1;
+
+const MAX_MEASURES: i32 = 1024;
+const MIN_BPM: f64 = 30.;
+const MAX_BPM: f64 = 600.;
+const BPM_ITERATIONS: i32 = 40;
+
+// Lowest BPM at which `rating` reaches `target`, assuming it increases with BPM.
+// None if outside the range of plausible BPMs.
+pub fn bpm_reaching(target: f64, rating: impl Fn(f64) -> f64) -> Option<f64> {
+    if rating(MIN_BPM) >= target || rating(MAX_BPM) < target {
+        return None;
+    }
+    // rating(low) < target <= rating(high)
+    let (mut low, mut high) = (MIN_BPM, MAX_BPM);
+    for _ in 0..BPM_ITERATIONS {
+        let mid = (low + high) / 2.;
+        if rating(mid) < target {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some(high)
+}
+
+// Answers questions about unbroken streams of 16ths under a model, assuming
+// ratings increase with both BPM and length.
+pub struct Solver<'a> {
+    pub model: &'a dyn RatingModel,
+    pub params: &'a [F1],
+    pub calibration: &'a Calibration,
+}
+
+impl Solver<'_> {
+    fn rating(&self, bpm: f64, measures: i32) -> f64 {
+        let chart = Chart::stream_unbroken(bpm, measures, 0);
+        self.calibration
+            .apply(self.model.rate(&chart, self.params).0.x)
+    }
+
+    // Fewest measures at `bpm` rated at least `meter`, None if even MAX_MEASURES
+    // measures aren't.
+    pub fn measures_for(&self, bpm: f64, meter: i32) -> Option<i32> {
+        let meter = meter as f64;
+        if self.rating(bpm, MAX_MEASURES) < meter {
+            return None;
+        }
+        // rating(low) < meter <= rating(high), treating 0 measures as too easy
+        let (mut low, mut high) = (0, MAX_MEASURES);
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if self.rating(bpm, mid) < meter {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Some(high)
+    }
+
+    // Lowest BPM at which `measures` measures are rated at least `meter`, None if
+    // outside the range of plausible BPMs.
+    pub fn bpm_for(&self, measures: i32, meter: i32) -> Option<f64> {
+        bpm_reaching(meter as f64, |bpm| self.rating(bpm, measures))
+    }
+}
+
+#[test]
+fn test_solver() {
+    use crate::rate::{constants, Fatigue};
+    let params = constants(&Fatigue.default_params());
+    let solver = Solver {
+        model: &Fatigue,
+        params: &params,
+        calibration: &Calibration::Identity,
+    };
+    let meter = solver.rating(190., 64).floor() as i32;
+    let measures = solver.measures_for(190., meter).unwrap();
+    assert!(measures <= 64);
+    assert!(solver.rating(190., measures) >= meter as f64);
+    assert!(solver.rating(190., measures - 1) < meter as f64);
+    let bpm = solver.bpm_for(64, meter).unwrap();
+    assert!(bpm <= 190.);
+    assert!((solver.rating(bpm, 64) - meter as f64).abs() < 1e-6);
+    assert_eq!(solver.measures_for(60., 30), None);
+    assert_eq!(solver.bpm_for(64, 1), None);
+}
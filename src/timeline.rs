@@ -0,0 +1,114 @@
+use crate::chart::Chart;
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+// Model state after a note, located in the chart. Written as `value` whatever
+// the model, e.g. fatigue for the fatigue models.
+#[derive(Debug, PartialEq)]
+pub struct Point {
+    pub time: f64,
+    // starting from 0, like measures in .sm files
+    pub measure: usize,
+    pub beat: f64,
+    pub bpm: f64,
+    pub value: f64,
+}
+
+pub fn measure(beat: f64) -> usize {
+    // round to avoid floating point error putting a note on a downbeat into the
+    // previous measure
+    ((beat * 48.).round() / 192.) as usize
+}
+
+// Locates each (time, value) state returned by `RatingModel::rate`.
+pub fn timeline(chart: &Chart, states: &[(f64, f64)]) -> Vec<Point> {
+    states
+        .iter()
+        .map(|(time, value)| {
+            let (beat, bpm) = chart.beat_and_bpm(*time);
+            Point {
+                time: *time,
+                measure: measure(beat),
+                beat,
+                bpm,
+                value: *value,
+            }
+        })
+        .collect()
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub fn write(chart: &Chart, points: &[Point], format: Format) -> String {
+    match format {
+        Format::Csv => {
+            let mut csv = String::from("time,measure,beat,bpm,value\n");
+            for p in points {
+                csv.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    p.time, p.measure, p.beat, p.bpm, p.value
+                ));
+            }
+            csv
+        }
+        Format::Json => format!(
+            "{{\"title\":{},\"difficulty\":{},\"rating\":{},\"timeline\":[{}]}}\n",
+            json_string(&chart.title),
+            json_string(&chart.difficulty),
+            chart.rating,
+            points
+                .iter()
+                .map(|p| format!(
+                    "{{\"time\":{},\"measure\":{},\"beat\":{},\"bpm\":{},\"value\":{}}}",
+                    p.time, p.measure, p.beat, p.bpm, p.value
+                ))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+    }
+}
+
+#[test]
+fn test_timeline() {
+    let mut chart = Chart::stream_unbroken(120., 2, 15);
+    chart.title = "a \"b\"".to_owned();
+    let points = timeline(&chart, &[(0., 0.), (1.875, 1.5), (2., 2.)]);
+    assert_eq!(
+        points[1],
+        Point {
+            time: 1.875,
+            measure: 0,
+            beat: 3.75,
+            bpm: 120.,
+            value: 1.5
+        }
+    );
+    assert_eq!(points[2].measure, 1);
+    assert_eq!(
+        write(&chart, &points[1..], Format::Csv),
+        "time,measure,beat,bpm,value\n1.875,0,3.75,120,1.5\n2,1,4,120,2\n"
+    );
+    assert_eq!(
+        write(&chart, &points[2..], Format::Json),
+        "{\"title\":\"a \\\"b\\\"\",\"difficulty\":\"\",\"rating\":15,\"timeline\":[{\"time\":2,\"measure\":1,\"beat\":4,\"bpm\":120,\"value\":2}]}\n"
+    );
+    assert_eq!(json_string("\\\n\u{1}"), "\"\\\\\\n\\u0001\"");
+}
@@ -1,65 +1,606 @@
+use crate::calibrate::{self, Calibration};
 use crate::chart::Chart;
-use crate::rate::{rate, Params};
-use autodiff::F1;
+use crate::rate::{constants, RatingModel};
+use autodiff::{Float, F1};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-fn error(charts: &[Chart], params: Params) -> F1 {
-    let mut error = F1::cst(0.);
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Loss {
+    /// Squared error against the meter
+    #[default]
+    Squared,
+    /// Squared error within one meter, absolute error beyond it
+    Huber,
+    /// Absolute error against the meter
+    Absolute,
+    /// Pairwise hinge loss on charts rated less than a margin apart in the order of their meters, needs --calibration
+    Ranking,
+    /// Logistic loss against learned thresholds between adjacent meters
+    Ordinal,
+}
+
+impl Loss {
+    // Trainable parameters the loss needs on top of the model params.
+    fn initial_extra_params(self, meters: &[i32]) -> Vec<f64> {
+        match self {
+            // A chart rated m should land between the thresholds m and m + 1,
+            // matching the m + 0.5 target of the other losses. Thresholds after
+            // the first are trained as gaps, see `thresholds`.
+            Loss::Ordinal => meters
+                .iter()
+                .skip(1)
+                .enumerate()
+                .map(|(i, m)| match i {
+                    0 => *m as f64,
+                    _ => inverse_softplus((m - meters[i]) as f64),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Ordinal thresholds from the first threshold followed by softplus gaps, so
+// they stay increasing whatever the gradient does to the extra params.
+fn thresholds(extra: &[F1]) -> Vec<F1> {
+    let mut thresholds = Vec::<F1>::new();
+    for x in extra {
+        thresholds.push(match thresholds.last() {
+            Some(last) => *last + softplus(*x),
+            None => *x,
+        });
+    }
+    thresholds
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Weighting {
+    /// Only use the per-chart weights
+    #[default]
+    None,
+    /// Weight charts inversely to how many charts share their meter
+    Meter,
+    /// Weight charts inversely to how many charts share their pack
+    Pack,
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub max_iterations: i32,
+    pub loss: Loss,
+    pub weighting: Weighting,
+    pub checkpoint_path: Option<PathBuf>,
+    pub checkpoint_every: u32,
+    pub resume: Option<Checkpoint>,
+    // fraction of charts held out to compute validation error
+    pub validation_fraction: f64,
+    pub calibration: calibrate::Kind,
+}
+
+// Per-chart weights combining `Chart::weight`, `Chart::confidence` and `weighting`.
+fn sample_weights(charts: &[&Chart], weighting: Weighting) -> Vec<f64> {
+    let mut counts = HashMap::<String, usize>::new();
+    let key = |chart: &Chart| match weighting {
+        Weighting::None => String::new(),
+        Weighting::Meter => chart.rating.to_string(),
+        Weighting::Pack => chart.pack(),
+    };
     for chart in charts {
-        let (rating, _) = rate(chart, params);
-        let dr = rating - F1::cst(chart.rating as f64 + 0.5);
-        error += dr * dr;
+        *counts.entry(key(chart)).or_default() += 1;
+    }
+    charts
+        .iter()
+        .map(|chart| {
+            let frequency = match weighting {
+                Weighting::None => 1.0,
+                _ => counts[&key(chart)] as f64,
+            };
+            chart.weight * chart.confidence / frequency
+        })
+        .collect()
+}
+
+// Distinct meters in ascending order.
+fn meters(charts: &[&Chart]) -> Vec<i32> {
+    let mut meters = charts.iter().map(|c| c.rating).collect::<Vec<_>>();
+    meters.sort();
+    meters.dedup();
+    meters
+}
+
+fn softplus(x: F1) -> F1 {
+    // avoid overflowing exp() for large inputs, where softplus(x) ~= x
+    if x.value() > 30. {
+        x
+    } else {
+        x.exp().ln_1p()
+    }
+}
+
+fn inverse_softplus(y: f64) -> f64 {
+    if y > 30. {
+        y
+    } else {
+        y.exp_m1().ln()
+    }
+}
+
+// Charts to compute the error over, with their sample weights.
+struct Dataset<'a> {
+    charts: Vec<&'a Chart>,
+    weights: Vec<f64>,
+    // meters of all charts, training and validation, so that both use the same
+    // ordinal thresholds
+    meters: Vec<i32>,
+}
+
+impl<'a> Dataset<'a> {
+    fn new(charts: Vec<&'a Chart>, weighting: Weighting, meters: Vec<i32>) -> Self {
+        Self {
+            weights: sample_weights(&charts, weighting),
+            charts,
+            meters,
+        }
+    }
+}
+
+// Weighted least squares fit of `ratings` to meter + 0.5.
+fn affine_calibrate(ratings: &[F1], charts: &[&Chart], weights: &[f64]) -> Vec<F1> {
+    let total_weight = weights.iter().sum::<f64>();
+    let targets = charts
+        .iter()
+        .map(|c| c.rating as f64 + 0.5)
+        .collect::<Vec<_>>();
+    let mut mean_rating = F1::cst(0.);
+    let mut mean_target = 0.;
+    for ((rating, target), weight) in ratings.iter().zip(&targets).zip(weights) {
+        mean_rating += *rating * F1::cst(*weight);
+        mean_target += target * weight;
+    }
+    let mean_rating = mean_rating / F1::cst(total_weight);
+    let mean_target = mean_target / total_weight;
+    let mut cov = F1::cst(0.);
+    let mut var = F1::cst(0.);
+    for ((rating, target), weight) in ratings.iter().zip(&targets).zip(weights) {
+        let dr = *rating - mean_rating;
+        cov += dr * F1::cst((target - mean_target) * weight);
+        var += dr * dr * F1::cst(*weight);
+    }
+    if var.value() == 0. {
+        return vec![F1::cst(mean_target); ratings.len()];
+    }
+    let scale = cov / var;
+    ratings
+        .iter()
+        .map(|r| (*r - mean_rating) * scale + F1::cst(mean_target))
+        .collect()
+}
+
+// Whether training fits ratings to meters with a scale and offset before computing
+// the error, so the model output only has to be proportional to meters. Ordinal
+// thresholds already adapt to the scale of the ratings.
+fn calibrates(options: &Options) -> bool {
+    options.calibration != calibrate::Kind::None && !matches!(options.loss, Loss::Ordinal)
+}
+
+fn ratings(data: &Dataset, model: &dyn RatingModel, params: &[F1]) -> Vec<F1> {
+    data.charts
+        .iter()
+        .map(|chart| model.rate(chart, params).0)
+        .collect()
+}
+
+// Fits a calibration of the model's ratings to the meters of `data`.
+fn fit_calibration(
+    data: &Dataset,
+    model: &dyn RatingModel,
+    params: &[F1],
+    kind: calibrate::Kind,
+) -> Calibration {
+    let raw = ratings(data, model, params)
+        .iter()
+        .map(|r| r.value())
+        .collect::<Vec<_>>();
+    let meters = data.charts.iter().map(|c| c.rating).collect::<Vec<_>>();
+    Calibration::fit(kind, &raw, &meters)
+}
+
+fn error(data: &Dataset, model: &dyn RatingModel, params: &[F1], loss: Loss, extra: &[F1]) -> F1 {
+    error_of(data, &ratings(data, model, params), loss, extra)
+}
+
+// Error with ratings mapped through a calibration fit elsewhere, e.g. on the
+// training charts when computing validation error.
+fn calibrated_error(
+    data: &Dataset,
+    model: &dyn RatingModel,
+    params: &[F1],
+    calibration: &Calibration,
+    loss: Loss,
+    extra: &[F1],
+) -> F1 {
+    let ratings = ratings(data, model, params)
+        .iter()
+        .map(|r| F1::cst(calibration.apply(r.value())))
+        .collect::<Vec<_>>();
+    error_of(data, &ratings, loss, extra)
+}
+
+fn error_of(data: &Dataset, ratings: &[F1], loss: Loss, extra: &[F1]) -> F1 {
+    const HUBER_DELTA: f64 = 1.0;
+    // how far apart in meters charts with different meters should be rated, so
+    // that squashing every rating together doesn't lower the ranking loss
+    const RANKING_MARGIN: f64 = 0.5;
+    let (charts, weights) = (&data.charts, &data.weights);
+    let total_weight = weights.iter().sum::<f64>();
+    let mut error = F1::cst(0.);
+    match loss {
+        Loss::Squared | Loss::Huber | Loss::Absolute => {
+            for ((chart, rating), weight) in charts.iter().zip(ratings).zip(weights) {
+                let dr = *rating - F1::cst(chart.rating as f64 + 0.5);
+                error += F1::cst(*weight)
+                    * match loss {
+                        Loss::Squared => dr * dr,
+                        Loss::Absolute => dr.abs(),
+                        _ if dr.abs().value() <= HUBER_DELTA => dr * dr * F1::cst(0.5),
+                        _ => (dr.abs() - F1::cst(0.5 * HUBER_DELTA)) * F1::cst(HUBER_DELTA),
+                    };
+            }
+            error / F1::cst(total_weight)
+        }
+        Loss::Ranking => {
+            let mut pairs_weight = 0.;
+            for i in 0..charts.len() {
+                for j in i + 1..charts.len() {
+                    let (chart_i, rating_i) = (&charts[i], &ratings[i]);
+                    let (chart_j, rating_j) = (&charts[j], &ratings[j]);
+                    let (lower, higher) = match chart_i.rating.cmp(&chart_j.rating) {
+                        std::cmp::Ordering::Less => (rating_i, rating_j),
+                        std::cmp::Ordering::Greater => (rating_j, rating_i),
+                        std::cmp::Ordering::Equal => continue,
+                    };
+                    let weight = weights[i] * weights[j];
+                    pairs_weight += weight;
+                    let violation = F1::cst(RANKING_MARGIN) - (*higher - *lower);
+                    if violation.value() > 0. {
+                        error += violation * F1::cst(weight);
+                    }
+                }
+            }
+            if pairs_weight == 0. {
+                error
+            } else {
+                error / F1::cst(pairs_weight)
+            }
+        }
+        Loss::Ordinal => {
+            let thresholds = thresholds(extra);
+            let boundaries = data.meters.iter().skip(1).zip(&thresholds);
+            for ((chart, rating), weight) in charts.iter().zip(ratings).zip(weights) {
+                for (meter, threshold) in boundaries.clone() {
+                    error += F1::cst(*weight)
+                        * if chart.rating >= *meter {
+                            softplus(*threshold - *rating)
+                        } else {
+                            softplus(*rating - *threshold)
+                        };
+                }
+            }
+            error / F1::cst(total_weight)
+        }
+    }
+}
+
+// Error minimized while training, including the model's regularization. Ratings
+// are fit to the meters with a scale and offset first if `calibrate`, kept
+// differentiable so the model is trained through the calibration.
+fn objective(
+    data: &Dataset,
+    model: &dyn RatingModel,
+    params: &[F1],
+    loss: Loss,
+    extra: &[F1],
+    calibrate: bool,
+) -> F1 {
+    let error = if calibrate {
+        let ratings = ratings(data, model, params);
+        let ratings = affine_calibrate(&ratings, &data.charts, &data.weights);
+        error_of(data, &ratings, loss, extra)
+    } else {
+        error(data, model, params, loss, extra)
+    };
+    error + model.regularization(params)
+}
+
+// Error on `validation`, mapping ratings to meters with the calibration fit on
+// `training` so the held out meters aren't used to fit it.
+fn validation_error(
+    model: &dyn RatingModel,
+    training: &Dataset,
+    validation: &Dataset,
+    params: &[F1],
+    extra: &[F1],
+    options: &Options,
+) -> Option<f64> {
+    if validation.charts.is_empty() {
+        return None;
+    }
+    let calibration = if calibrates(options) {
+        fit_calibration(training, model, params, options.calibration)
+    } else {
+        Calibration::Identity
+    };
+    Some(calibrated_error(validation, model, params, &calibration, options.loss, extra).value())
+}
+
+const INITIAL_LEARNING_RATE: f64 = 0.001;
+
+// The command line spelling of an option value.
+fn name<T: clap::ValueEnum>(v: &T) -> String {
+    v.to_possible_value().unwrap().get_name().to_owned()
+}
+
+// Everything needed to continue training where it left off, along with the
+// options that best_err depends on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub model: String,
+    pub loss: Loss,
+    pub weighting: Weighting,
+    pub calibration: calibrate::Kind,
+    pub validation_fraction: f64,
+    pub iteration: i32,
+    pub learning_rate: f64,
+    // params being trained, followed by any extra params the loss needs
+    pub params: Vec<f64>,
+    pub best_params: Vec<f64>,
+    pub best_err: f64,
+}
+
+impl Checkpoint {
+    fn new(model: &dyn RatingModel, params: Vec<f64>, options: &Options) -> Self {
+        Self {
+            model: model.name().to_owned(),
+            loss: options.loss,
+            weighting: options.weighting,
+            calibration: options.calibration,
+            validation_fraction: options.validation_fraction,
+            iteration: 0,
+            learning_rate: INITIAL_LEARNING_RATE,
+            best_params: params.clone(),
+            params,
+            best_err: f64::INFINITY,
+        }
+    }
+
+    fn parse(buf: &str) -> Option<Self> {
+        let mut map = HashMap::new();
+        for line in buf.lines() {
+            let (key, val) = line.split_once('=')?;
+            map.insert(key.trim(), val.trim());
+        }
+        let split = |v: &str| {
+            v.split(',')
+                .map(|x| x.parse::<f64>().ok())
+                .collect::<Option<Vec<_>>>()
+        };
+        fn value<T: clap::ValueEnum>(v: &str) -> Option<T> {
+            T::from_str(v, true).ok()
+        }
+        Some(Self {
+            model: map.get("model")?.to_string(),
+            loss: value(map.get("loss")?)?,
+            weighting: value(map.get("weighting")?)?,
+            calibration: value(map.get("calibration")?)?,
+            validation_fraction: map.get("validation_fraction")?.parse().ok()?,
+            iteration: map.get("iteration")?.parse().ok()?,
+            learning_rate: map.get("learning_rate")?.parse().ok()?,
+            params: split(map.get("params")?)?,
+            best_params: split(map.get("best_params")?)?,
+            best_err: map.get("best_err")?.parse().ok()?,
+        })
+    }
+
+    pub fn read(path: &Path) -> Option<Self> {
+        Self::parse(&std::fs::read_to_string(path).ok()?)
+    }
+
+    fn write(&self, path: &Path) {
+        // write to a temporary file first so that an interruption never leaves a
+        // truncated checkpoint behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.to_string()).expect("couldn't write checkpoint");
+        std::fs::rename(&tmp, path).expect("couldn't write checkpoint");
+    }
+}
+
+impl std::fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let join = |v: &[f64]| {
+            v.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        writeln!(f, "model={}", self.model)?;
+        writeln!(f, "loss={}", name(&self.loss))?;
+        writeln!(f, "weighting={}", name(&self.weighting))?;
+        writeln!(f, "calibration={}", name(&self.calibration))?;
+        writeln!(f, "validation_fraction={}", self.validation_fraction)?;
+        writeln!(f, "iteration={}", self.iteration)?;
+        writeln!(f, "learning_rate={}", self.learning_rate)?;
+        writeln!(f, "params={}", join(&self.params))?;
+        writeln!(f, "best_params={}", join(&self.best_params))?;
+        writeln!(f, "best_err={}", self.best_err)
+    }
+}
+
+#[test]
+fn test_checkpoint() {
+    let checkpoint = Checkpoint {
+        model: "fatigue".to_owned(),
+        loss: Loss::Huber,
+        weighting: Weighting::Pack,
+        calibration: calibrate::Kind::Isotonic,
+        validation_fraction: 0.25,
+        iteration: 12,
+        learning_rate: 0.5,
+        params: vec![1., 2.5, 3.],
+        best_params: vec![0.1, 0.2, 0.3],
+        best_err: 4.25,
+    };
+    assert_eq!(Checkpoint::parse(&checkpoint.to_string()), Some(checkpoint));
+    assert_eq!(Checkpoint::parse("iteration=12"), None);
+    assert_eq!(Checkpoint::parse("hello"), None);
+}
+
+// One row of training history per iteration.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub iteration: i32,
+    pub params: Vec<f64>,
+    pub train_err: f64,
+    pub validation_err: Option<f64>,
+    pub learning_rate: f64,
+    // whether this is the first iteration after restarting from mutated params
+    pub restart: bool,
+}
+
+// Appends `history` to a CSV file, writing the header if the file is new.
+pub fn write_history(path: &Path, history: &[HistoryEntry]) {
+    use std::io::Write;
+    let exists = path.exists();
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("couldn't open history file");
+    if !exists {
+        writeln!(
+            file,
+            "iteration,train_err,validation_err,learning_rate,restart,params"
+        )
+        .unwrap();
+    }
+    for entry in history {
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            entry.iteration,
+            entry.train_err,
+            entry
+                .validation_err
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+            entry.learning_rate,
+            entry.restart as i32,
+            entry
+                .params
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+        .unwrap();
     }
-    error / F1::cst(charts.len() as f64)
 }
 
 fn train_until_plateau(
-    charts: &[Chart],
-    params: Params,
-    max_iterations: i32,
-    iteration: &mut i32,
-) -> (Params, F1) {
+    model: &dyn RatingModel,
+    training: &Dataset,
+    validation: &Dataset,
+    num_params: usize,
+    state: &mut Checkpoint,
+    history: &mut Vec<HistoryEntry>,
+    options: &Options,
+) {
     const LEARNING_RATE_INCREASE_MULTIPLIER: f64 = 1.1;
     const LEARNING_RATE_DECREASE_MULTIPLIER: f64 = 0.5;
     const INCREASE_LEARNING_RATE_AFTER_ITERATIONS: i32 = 10;
     const PLATEAU_ITERATIONS: i32 = 20;
     const PLATEAU_IMPROVEMENT: f64 = 0.002;
-    let mut learning_rate = 0.001;
-    let mut v = params.to_vec();
-    let mut best_err = error(charts, Params::from_vec(&v));
+    let loss = options.loss;
+    let calibrate = calibrates(options);
+    let bounds = model.param_bounds();
+    let eval = |data: &Dataset, v: &[f64]| {
+        objective(
+            data,
+            model,
+            &constants(&v[..num_params]),
+            loss,
+            &constants(&v[num_params..]),
+            calibrate,
+        )
+    };
+    let validation_err = |v: &[f64]| {
+        validation_error(
+            model,
+            training,
+            validation,
+            &constants(&v[..num_params]),
+            &constants(&v[num_params..]),
+            options,
+        )
+    };
+    let mut v = state.params.clone();
+    let mut best_err = eval(training, &v);
     println!("initial err: {}", best_err.x);
+    if best_err.x < state.best_err {
+        state.best_params = v.clone();
+        state.best_err = best_err.x;
+    }
+    history.push(HistoryEntry {
+        iteration: state.iteration,
+        params: v.clone(),
+        train_err: best_err.x,
+        validation_err: validation_err(&v),
+        learning_rate: state.learning_rate,
+        restart: true,
+    });
     let mut last_plateau_error = best_err;
     let mut check_plateau_iterations = PLATEAU_ITERATIONS;
     let mut iterations_since_last_learning_rate_change = 0;
-    while *iteration < max_iterations {
-        println!("iteration {iteration}, learning rate {learning_rate}");
-        *iteration += 1;
+    while state.iteration < options.max_iterations {
+        println!(
+            "iteration {}, learning rate {}",
+            state.iteration, state.learning_rate
+        );
+        state.iteration += 1;
+        if let Some(path) = &options.checkpoint_path {
+            if (state.iteration as u32).is_multiple_of(options.checkpoint_every) {
+                state.write(path);
+            }
+        }
         let grad = autodiff::grad(
             |x| {
-                error(
-                    charts,
-                    Params {
-                        step_dt_mult: x[0],
-                        step_dt_add: x[1],
-                        ratio_dt_mult: x[2],
-                    },
+                objective(
+                    training,
+                    model,
+                    &x[..num_params],
+                    loss,
+                    &x[num_params..],
+                    calibrate,
                 )
             },
             &v,
         );
         let mut v_new = v.clone();
-        for (x, g) in v_new.iter_mut().zip(grad.iter()) {
+        for (i, (x, g)) in v_new.iter_mut().zip(grad.iter()).enumerate() {
             if !g.is_finite() {
                 println!("invalid gradient {g}");
                 std::process::exit(1);
             }
-            *x -= g * learning_rate;
-            *x = x.max(0.0);
+            *x -= g * state.learning_rate;
+            if let Some((min, max)) = bounds.get(i) {
+                *x = x.clamp(*min, *max);
+            }
         }
         println!("updated params: {:?}", &v_new);
-        let err = error(charts, Params::from_vec(&v_new));
+        let err = eval(training, &v_new);
         println!("err {}", err.value());
         if err > best_err {
-            learning_rate *= LEARNING_RATE_DECREASE_MULTIPLIER;
+            state.learning_rate *= LEARNING_RATE_DECREASE_MULTIPLIER;
             println!("new error higher than previous, retrying with smaller learning rate");
             iterations_since_last_learning_rate_change = 0;
             continue;
@@ -75,50 +616,423 @@ fn train_until_plateau(
         }
         v = v_new;
         best_err = err;
+        state.params = v.clone();
+        if best_err.x < state.best_err {
+            state.best_params = v.clone();
+            state.best_err = best_err.x;
+        }
+        history.push(HistoryEntry {
+            iteration: state.iteration,
+            params: v.clone(),
+            train_err: best_err.x,
+            validation_err: validation_err(&v),
+            learning_rate: state.learning_rate,
+            restart: false,
+        });
         iterations_since_last_learning_rate_change += 1;
         if iterations_since_last_learning_rate_change > INCREASE_LEARNING_RATE_AFTER_ITERATIONS {
             iterations_since_last_learning_rate_change = 0;
-            learning_rate *= LEARNING_RATE_INCREASE_MULTIPLIER;
+            state.learning_rate *= LEARNING_RATE_INCREASE_MULTIPLIER;
             println!("increasing training rate");
         }
     }
-
-    (Params::from_vec(&v), best_err)
 }
 
-fn mutate_params(mut params: Params) -> Params {
+fn mutate_params(model: &dyn RatingModel, params: &mut [f64]) {
     use rand::distributions::{Distribution, Uniform};
     use rand::Rng;
     let mut rng = rand::thread_rng();
     let range = Uniform::from(0.9..1.1);
-    if rng.gen() {
-        params.step_dt_mult *= range.sample(&mut rng);
+    for (x, (min, max)) in params.iter_mut().zip(model.param_bounds()) {
+        if rng.gen() {
+            *x = (*x * range.sample(&mut rng)).clamp(min, max);
+        }
     }
-    if rng.gen() {
-        params.step_dt_add *= range.sample(&mut rng);
+}
+
+// Whether a chart is held out for validation. Based on a hash of the chart rather
+// than its position so the split is the same across runs regardless of the order
+// charts are read in. Uses FNV-1a since std's hasher may change between releases.
+fn is_validation(chart: &Chart, validation_fraction: f64) -> bool {
+    let path = chart
+        .path
+        .as_ref()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in path.bytes().chain([0]).chain(chart.description().bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
-    if rng.gen() {
-        params.ratio_dt_mult *= range.sample(&mut rng);
+    (hash % 10000) as f64 / 10000. < validation_fraction
+}
+
+// Parses a validation fraction, which must leave some charts to train on.
+pub fn validation_fraction(s: &str) -> Result<f64, String> {
+    let fraction = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if (0. ..1.).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err("must be at least 0 and less than 1".to_owned())
     }
-    params
 }
 
-pub fn train(charts: &[Chart], params: Params, max_iterations: i32) -> (Params, f64) {
-    let mut iteration = 0;
-    let (mut best_params, mut best_err) =
-        train_until_plateau(charts, params, max_iterations, &mut iteration);
-    while iteration < max_iterations {
+pub struct Trained {
+    pub params: Vec<f64>,
+    // maps the model's ratings to meters, fit on the training charts
+    pub calibration: Calibration,
+    pub train_err: f64,
+    pub validation_err: Option<f64>,
+    pub history: Vec<HistoryEntry>,
+}
+
+pub fn train(
+    model: &dyn RatingModel,
+    charts: &[Chart],
+    params: &[f64],
+    options: &Options,
+) -> Trained {
+    train_on(model, &charts.iter().collect::<Vec<_>>(), params, options)
+}
+
+// Fits the model on `samples` resamples of the charts drawn with replacement,
+// each starting from `params`.
+pub fn bootstrap(
+    model: &dyn RatingModel,
+    charts: &[Chart],
+    params: &[f64],
+    options: &Options,
+    samples: usize,
+) -> Vec<Trained> {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    // checkpoints are for a single long run
+    let options = Options {
+        checkpoint_path: None,
+        resume: None,
+        ..options.clone()
+    };
+    (0..samples)
+        .map(|i| {
+            println!("bootstrap sample {}/{samples}", i + 1);
+            let resampled = (0..charts.len())
+                .map(|_| &charts[rng.gen_range(0..charts.len())])
+                .collect::<Vec<_>>();
+            train_on(model, &resampled, params, &options)
+        })
+        .collect()
+}
+
+fn train_on(
+    model: &dyn RatingModel,
+    charts: &[&Chart],
+    params: &[f64],
+    options: &Options,
+) -> Trained {
+    let all_meters = meters(charts);
+    let (validation, training): (Vec<&Chart>, Vec<&Chart>) = charts
+        .iter()
+        .partition(|c| is_validation(c, options.validation_fraction));
+    println!(
+        "training on {} charts, validating on {}",
+        training.len(),
+        validation.len()
+    );
+    if training.is_empty() {
+        println!("no charts left to train on, lower --validation-fraction");
+        std::process::exit(1);
+    }
+    let training = Dataset::new(training, options.weighting, all_meters.clone());
+    let validation = Dataset::new(validation, options.weighting, all_meters.clone());
+    let num_params = params.len();
+    let mut v = params.to_vec();
+    v.extend(options.loss.initial_extra_params(&all_meters));
+    let mut state = match &options.resume {
+        Some(checkpoint) => {
+            if checkpoint.model != model.name() {
+                println!("checkpoint is for model {}", checkpoint.model);
+                std::process::exit(1);
+            }
+            if checkpoint.params.len() != v.len() || checkpoint.best_params.len() != v.len() {
+                println!("checkpoint has the wrong number of params for this loss and charts");
+                std::process::exit(1);
+            }
+            // best_err is only comparable under the options it was computed with
+            let options_match = checkpoint.loss == options.loss
+                && checkpoint.weighting == options.weighting
+                && checkpoint.calibration == options.calibration
+                && checkpoint.validation_fraction == options.validation_fraction;
+            if !options_match {
+                println!(
+                    "checkpoint was trained with --loss {} --weighting {} --calibration {} --validation-fraction {}",
+                    name(&checkpoint.loss),
+                    name(&checkpoint.weighting),
+                    name(&checkpoint.calibration),
+                    checkpoint.validation_fraction
+                );
+                std::process::exit(1);
+            }
+            println!("resuming from iteration {}", checkpoint.iteration);
+            checkpoint.clone()
+        }
+        None => Checkpoint::new(model, v, options),
+    };
+    let mut history = Vec::new();
+    train_until_plateau(
+        model,
+        &training,
+        &validation,
+        num_params,
+        &mut state,
+        &mut history,
+        options,
+    );
+    while state.iteration < options.max_iterations {
         println!("mutating params and retrying");
-        let (test_trained_params, test_trained_err) = train_until_plateau(
-            charts,
-            mutate_params(best_params),
-            max_iterations,
-            &mut iteration,
+        state.params = state.best_params.clone();
+        mutate_params(model, &mut state.params[..num_params]);
+        state.learning_rate = INITIAL_LEARNING_RATE;
+        train_until_plateau(
+            model,
+            &training,
+            &validation,
+            num_params,
+            &mut state,
+            &mut history,
+            options,
         );
-        if test_trained_err < best_err {
-            best_params = test_trained_params;
-            best_err = test_trained_err;
-        }
     }
-    (best_params, best_err.x)
+    if let Some(path) = &options.checkpoint_path {
+        state.write(path);
+    }
+    let best_extra = &state.best_params[num_params..];
+    let best_params = state.best_params[..num_params].to_vec();
+    let best = constants(&best_params);
+    let calibration = match options.loss {
+        // the learned thresholds already map ratings to meters
+        Loss::Ordinal => Calibration::Thresholds {
+            lowest: all_meters[0],
+            thresholds: thresholds(&constants(best_extra))
+                .iter()
+                .map(|t| t.value())
+                .zip(all_meters.iter().skip(1).copied())
+                .collect(),
+        },
+        _ => fit_calibration(&training, model, &best, options.calibration),
+    };
+    let validation_err = validation_error(
+        model,
+        &training,
+        &validation,
+        &best,
+        &constants(best_extra),
+        options,
+    );
+    Trained {
+        params: best_params,
+        calibration,
+        train_err: state.best_err,
+        validation_err,
+        history,
+    }
+}
+
+#[test]
+fn loss_sanity_tests() {
+    use crate::rate::Fatigue;
+    let params = constants(&Fatigue.default_params());
+    let ordered = [
+        Chart::stream_unbroken(200., 16, 15),
+        Chart::stream_unbroken(200., 64, 16),
+    ];
+    let misordered = [
+        Chart::stream_unbroken(200., 16, 16),
+        Chart::stream_unbroken(200., 64, 15),
+    ];
+    let meters = vec![15, 16];
+    let ordered = Dataset::new(ordered.iter().collect(), Weighting::None, meters.clone());
+    let misordered = Dataset::new(misordered.iter().collect(), Weighting::None, meters);
+    assert_eq!(
+        error(&ordered, &Fatigue, &params, Loss::Ranking, &[]).value(),
+        0.
+    );
+    assert!(error(&misordered, &Fatigue, &params, Loss::Ranking, &[]).value() > 0.);
+    // rating everything the same doesn't avoid the ranking loss
+    let same = [F1::cst(15.), F1::cst(15.)];
+    assert_eq!(error_of(&ordered, &same, Loss::Ranking, &[]).value(), 0.5);
+    for loss in [Loss::Squared, Loss::Huber, Loss::Absolute] {
+        assert!(
+            error(&ordered, &Fatigue, &params, loss, &[])
+                < error(&misordered, &Fatigue, &params, loss, &[])
+        );
+    }
+    let thresholds = [F1::cst(16.)];
+    assert!(
+        error(&ordered, &Fatigue, &params, Loss::Ordinal, &thresholds)
+            < error(&misordered, &Fatigue, &params, Loss::Ordinal, &thresholds)
+    );
+}
+
+#[test]
+fn test_calibrated_error() {
+    use crate::rate::Fatigue;
+    let params = constants(&Fatigue.default_params());
+    let charts = [
+        Chart::stream_unbroken(200., 16, 15),
+        Chart::stream_unbroken(200., 64, 16),
+    ];
+    let data = Dataset::new(charts.iter().collect(), Weighting::None, vec![15, 16]);
+    assert!(objective(&data, &Fatigue, &params, Loss::Squared, &[], false).value() > 1.);
+    // any two distinct ratings are fit exactly by a scale and offset
+    assert!(objective(&data, &Fatigue, &params, Loss::Squared, &[], true).value() < 1e-9);
+}
+
+#[test]
+fn test_validation_calibration() {
+    use crate::rate::Fatigue;
+    let params = constants(&Fatigue.default_params());
+    let training = [
+        Chart::stream_unbroken(200., 16, 15),
+        Chart::stream_unbroken(200., 64, 16),
+    ];
+    // the same charts rated on a different scale need a different affine map
+    let validation = [
+        Chart::stream_unbroken(200., 16, 20),
+        Chart::stream_unbroken(200., 64, 25),
+    ];
+    let meters = vec![15, 16, 20, 25];
+    let training = Dataset::new(training.iter().collect(), Weighting::None, meters.clone());
+    let validation = Dataset::new(validation.iter().collect(), Weighting::None, meters);
+    let options = Options {
+        max_iterations: 0,
+        loss: Loss::Squared,
+        weighting: Weighting::None,
+        checkpoint_path: None,
+        checkpoint_every: 1,
+        resume: None,
+        validation_fraction: 0.,
+        calibration: calibrate::Kind::Affine,
+    };
+    let err = |data: &Dataset| validation_error(&Fatigue, &training, data, &params, &[], &options);
+    assert!(err(&training).unwrap() < 1e-9);
+    // refitting on the validation meters would make this 0 as well
+    assert!(err(&validation).unwrap() > 1.);
+    let empty = Dataset::new(Vec::new(), Weighting::None, vec![15, 16]);
+    assert_eq!(err(&empty), None);
+}
+
+#[test]
+fn test_sample_weights() {
+    let mut charts = [
+        Chart::stream_unbroken(200., 16, 15),
+        Chart::stream_unbroken(200., 32, 15),
+        Chart::stream_unbroken(200., 64, 16),
+    ];
+    assert_eq!(
+        sample_weights(&charts.iter().collect::<Vec<_>>(), Weighting::None),
+        vec![1., 1., 1.]
+    );
+    assert_eq!(
+        sample_weights(&charts.iter().collect::<Vec<_>>(), Weighting::Meter),
+        vec![0.5, 0.5, 1.]
+    );
+    assert_eq!(
+        sample_weights(&charts.iter().collect::<Vec<_>>(), Weighting::Pack),
+        vec![1. / 3.; 3]
+    );
+    charts[2].weight = 3.;
+    charts[2].confidence = 0.5;
+    assert_eq!(
+        sample_weights(&charts.iter().collect::<Vec<_>>(), Weighting::Meter),
+        vec![0.5, 0.5, 1.5]
+    );
+}
+
+#[test]
+fn test_is_validation() {
+    let charts = crate::presets::parse(crate::presets::DEFAULT).unwrap();
+    assert!(!charts.iter().any(|c| is_validation(c, 0.)));
+    assert!(charts.iter().all(|c| is_validation(c, 1.)));
+    let validation = charts.iter().filter(|c| is_validation(c, 0.5)).count();
+    assert!(validation > 0 && validation < charts.len());
+    // the hash is fixed, so the split is too
+    assert_eq!(
+        charts
+            .iter()
+            .position(|c| is_validation(c, 0.5))
+            .map(|i| charts[i].description()),
+        Some("384@170".to_owned())
+    );
+    assert_eq!(validation_fraction("0.25"), Ok(0.25));
+    assert!(validation_fraction("1").is_err());
+    assert!(validation_fraction("-0.5").is_err());
+    assert!(validation_fraction("half").is_err());
+}
+
+#[test]
+fn test_thresholds() {
+    let meters = [14, 15, 17, 18];
+    let extra = constants(&Loss::Ordinal.initial_extra_params(&meters));
+    let values = thresholds(&extra)
+        .iter()
+        .map(|t| t.value())
+        .collect::<Vec<_>>();
+    for (value, expected) in values.iter().zip([15., 17., 18.]) {
+        assert!((value - expected).abs() < 1e-9);
+    }
+    // gaps stay positive however negative their params get
+    let values = thresholds(&constants(&[15., -5., -5.]))
+        .iter()
+        .map(|t| t.value())
+        .collect::<Vec<_>>();
+    assert!(values[0] < values[1] && values[1] < values[2]);
+}
+
+#[test]
+fn test_recovery_needs_breaks() {
+    use crate::rate::RecoveryFatigue;
+    // recovery only kicks in on gaps longer than 8ths, so it's only trained by
+    // charts with arrowless breaks
+    let charts =
+        crate::presets::parse("180,16,15\n180,16,16,arrowless\n180,32,16\n180,16,17,8ths").unwrap();
+    let params = RecoveryFatigue.default_params();
+    let options = Options {
+        max_iterations: 10,
+        loss: Loss::Squared,
+        weighting: Weighting::None,
+        checkpoint_path: None,
+        checkpoint_every: 1,
+        resume: None,
+        validation_fraction: 0.,
+        calibration: calibrate::Kind::None,
+    };
+    let trained = train(&RecoveryFatigue, &charts, &params, &options);
+    assert_ne!(trained.params[3], params[3]);
+    assert_ne!(trained.params[4], params[4]);
+}
+
+#[test]
+fn test_bootstrap() {
+    use crate::rate::Fatigue;
+    let charts = [
+        Chart::stream_unbroken(180., 16, 15),
+        Chart::stream_unbroken(180., 64, 16),
+        Chart::stream_unbroken(200., 64, 17),
+    ];
+    let options = Options {
+        max_iterations: 3,
+        loss: Loss::Squared,
+        weighting: Weighting::None,
+        checkpoint_path: None,
+        checkpoint_every: 1,
+        resume: None,
+        validation_fraction: 0.,
+        calibration: calibrate::Kind::None,
+    };
+    let fits = bootstrap(&Fatigue, &charts, &Fatigue.default_params(), &options, 4);
+    assert_eq!(fits.len(), 4);
+    for fit in &fits {
+        assert_eq!(fit.params.len(), Fatigue.param_names().len());
+        assert!(fit.params.iter().all(|p| p.is_finite()));
+    }
 }
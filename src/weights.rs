@@ -0,0 +1,81 @@
+use crate::chart::Chart;
+use crate::manifest::lines;
+use std::path::PathBuf;
+
+// One line of a weights file: `path,difficulty,weight[,confidence]`.
+#[derive(PartialEq, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub difficulty: String,
+    pub weight: f64,
+    pub confidence: Option<f64>,
+}
+
+pub fn parse(buf: &str) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (line, fields) in lines(buf) {
+        if fields.len() != 3 && fields.len() != 4 {
+            println!("Expected path,difficulty,weight[,confidence]: {line}");
+            return None;
+        }
+        let Ok(weight) = fields[2].parse::<f64>() else {
+            println!("Invalid weight: {line}");
+            return None;
+        };
+        let confidence = match fields.get(3).map(|c| c.parse::<f64>()) {
+            None => None,
+            Some(Ok(c)) => Some(c),
+            Some(Err(_)) => {
+                println!("Invalid confidence: {line}");
+                return None;
+            }
+        };
+        entries.push(Entry {
+            path: PathBuf::from(fields[0]),
+            difficulty: fields[1].to_owned(),
+            weight,
+            confidence,
+        });
+    }
+    Some(entries)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse("# comment\n\n"), Some(vec![]));
+    assert_eq!(
+        parse("pack/song, Hard ,2\npack/other,,0.5,0.25"),
+        Some(vec![
+            Entry {
+                path: "pack/song".into(),
+                difficulty: "Hard".into(),
+                weight: 2.,
+                confidence: None,
+            },
+            Entry {
+                path: "pack/other".into(),
+                difficulty: "".into(),
+                weight: 0.5,
+                confidence: Some(0.25),
+            },
+        ])
+    );
+    assert_eq!(parse("pack/song,Hard"), None);
+    assert_eq!(parse("pack/song,Hard,heavy"), None);
+    assert_eq!(parse("pack/song,Hard,1,sure"), None);
+}
+
+// Sets the weight and confidence of charts listed in the file. Later entries take
+// precedence over earlier ones.
+pub fn apply(entries: &[Entry], charts: &mut [Chart]) {
+    for entry in entries {
+        for chart in charts.iter_mut() {
+            if chart.matches(&entry.path, &entry.difficulty) {
+                chart.weight = entry.weight;
+                if let Some(confidence) = entry.confidence {
+                    chart.confidence = confidence;
+                }
+            }
+        }
+    }
+}